@@ -6,6 +6,54 @@
 }:
 with lib; let
   cfg = config.services.log-normalizer;
+  format = pkgs.formats.toml {};
+  configFile = format.generate "log-normalizer.toml" (filterAttrsRecursive (_: value: value != null) {
+    poll_interval = cfg.pollInterval;
+    pool_size = cfg.poolSize;
+    raw_pool_size = cfg.rawPoolSize;
+    target_version = cfg.targetVersion;
+    map_catalog =
+      if cfg.mapCatalog != null
+      then "${cfg.mapCatalog}"
+      else null;
+    weapon_registry =
+      if cfg.weaponRegistry != null
+      then "${cfg.weaponRegistry}"
+      else null;
+    team_roster_overlap = cfg.teamRosterOverlap;
+    raw_log_channel = cfg.rawLogChannel;
+    refresh_views = cfg.refreshViews;
+    view_refresh_interval = cfg.viewRefreshInterval;
+    max_weapon_damage = cfg.maxWeaponDamage;
+    http_address = cfg.httpAddress;
+    stall_timeout = cfg.stallTimeout;
+    retry_attempts = cfg.retryAttempts;
+    retry_delay = cfg.retryDelay;
+    duplicate_window = cfg.duplicateWindow;
+    duplicate_roster_overlap = cfg.duplicateRosterOverlap;
+    duplicate_kill_difference = cfg.duplicateKillDifference;
+    matches = {
+      max_time_gap = cfg.matches.maxTimeGap;
+      min_roster_overlap = cfg.matches.minRosterOverlap;
+      min_score = cfg.matches.minScore;
+    };
+    merge = {
+      max_gap = cfg.merge.maxGap;
+      min_roster_overlap = cfg.merge.minRosterOverlap;
+      full_game_length = cfg.merge.fullGameLength;
+    };
+    validity = {
+      min_length = cfg.validity.minLength;
+      max_length = cfg.validity.maxLength;
+      max_kills = cfg.validity.maxKills;
+      max_deaths = cfg.validity.maxDeaths;
+      max_class_damage = cfg.validity.maxClassDamage;
+      max_damage_taken = cfg.validity.maxDamageTaken;
+      max_heals_received = cfg.validity.maxHealsReceived;
+      max_kill_streak = cfg.validity.maxKillStreak;
+      min_play_time_percent = cfg.validity.minPlayTimePercent;
+    };
+  });
 in {
   options.services.log-normalizer = {
     enable = mkEnableOption "Log normalizer";
@@ -25,6 +73,218 @@ in {
       description = "file containg RAW_DATABASE_URL variable";
     };
 
+    pollInterval = mkOption {
+      type = types.ints.positive;
+      default = 15 * 60;
+      description = "seconds between passes when no new logs are announced";
+    };
+
+    poolSize = mkOption {
+      type = types.ints.positive;
+      default = 2;
+      description = "number of connections to the log database";
+    };
+
+    rawPoolSize = mkOption {
+      type = types.ints.positive;
+      default = 2;
+      description = "number of connections to the raw log database";
+    };
+
+    targetVersion = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = "data version to upgrade old logs to, defaults to the latest version";
+    };
+
+    mapCatalog = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "map catalog to use instead of the bundled one";
+    };
+
+    weaponRegistry = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "weapon registry to use instead of the bundled one";
+    };
+
+    teamRosterOverlap = mkOption {
+      type = types.float;
+      default = 4.0 / 6.0;
+      description = "share of a roster that needs to match a known team to count as that team";
+    };
+
+    rawLogChannel = mkOption {
+      type = types.str;
+      default = "logs_raw";
+      description = "channel on the raw database that is notified when new logs are inserted";
+    };
+
+    refreshViews = mkOption {
+      type = types.bool;
+      default = true;
+      description = "refresh the materialized views after new logs are stored";
+    };
+
+    viewRefreshInterval = mkOption {
+      type = types.ints.unsigned;
+      default = 0;
+      example = 3600;
+      description = "minimum number of seconds between refreshes of the materialized views";
+    };
+
+    maxWeaponDamage = mkOption {
+      type = types.ints.positive;
+      default = 100000;
+      description = "weapon damage at or above this is ignored as broken";
+    };
+
+    httpAddress = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "127.0.0.1:9100";
+      description = "address to serve prometheus metrics on /metrics and health checks on /health and /ready";
+    };
+
+    stallTimeout = mkOption {
+      type = types.ints.positive;
+      default = 10 * 60;
+      description = "seconds a single log or view refresh can take before the normalizer is considered stuck";
+    };
+
+    retryAttempts = mkOption {
+      type = types.ints.positive;
+      default = 3;
+      description = "attempts for a single log before it is moved to the failed_logs table";
+    };
+
+    retryDelay = mkOption {
+      type = types.ints.unsigned;
+      default = 2;
+      description = "seconds before the first retry of a failed log, doubling with every attempt";
+    };
+
+    watchdogSec = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = 5 * 60;
+      description = "seconds without a watchdog ping from a live normalizer before systemd restarts it, disabled if null";
+    };
+
+    duplicateWindow = mkOption {
+      type = types.ints.unsigned;
+      default = 2 * 60 * 60;
+      description = "maximum seconds between the uploads of two copies of the same log";
+    };
+
+    duplicateRosterOverlap = mkOption {
+      type = types.float;
+      default = 0.8;
+      description = "share of each team that needs to be in both copies of the same log";
+    };
+
+    duplicateKillDifference = mkOption {
+      type = types.ints.unsigned;
+      default = 2;
+      description = "maximum difference in a team's kills per round between two copies of the same log";
+    };
+
+    matches = {
+      maxTimeGap = mkOption {
+        type = types.ints.positive;
+        default = 4 * 60 * 60;
+        description = "maximum seconds between the uploads of two logs of the same match";
+      };
+
+      minRosterOverlap = mkOption {
+        type = types.float;
+        default = 0.5;
+        description = "share of each team that needs to be in both logs of the same match";
+      };
+
+      minScore = mkOption {
+        type = types.float;
+        default = 0.6;
+        description = "minimum similarity score for two logs to be grouped into the same match";
+      };
+    };
+
+    merge = {
+      maxGap = mkOption {
+        type = types.ints.unsigned;
+        default = 15 * 60;
+        description = "maximum seconds between the end of one part of a split log and the start of the next";
+      };
+
+      minRosterOverlap = mkOption {
+        type = types.float;
+        default = 0.8;
+        description = "share of each team that needs to be in both parts of a split log";
+      };
+
+      fullGameLength = mkOption {
+        type = types.ints.unsigned;
+        default = 30 * 60;
+        description = "parts at least this many seconds long are only merged if their last round was cut off";
+      };
+    };
+
+    validity = {
+      minLength = mkOption {
+        type = types.ints.unsigned;
+        default = 60;
+        description = "logs need to be longer than this many seconds to be included in the stats";
+      };
+
+      maxLength = mkOption {
+        type = types.ints.positive;
+        default = 3600;
+        description = "logs need to be shorter than this many seconds to be included in the stats";
+      };
+
+      maxKills = mkOption {
+        type = types.ints.positive;
+        default = 100;
+        description = "players need less kills than this to be included in the stats";
+      };
+
+      maxDeaths = mkOption {
+        type = types.ints.positive;
+        default = 100;
+        description = "players need less deaths than this to be included in the stats";
+      };
+
+      maxClassDamage = mkOption {
+        type = types.ints.positive;
+        default = 50000;
+        description = "class stats need less damage than this to be included in the stats";
+      };
+
+      maxDamageTaken = mkOption {
+        type = types.ints.positive;
+        default = 100000;
+        description = "players need less damage taken than this to be included in the stats";
+      };
+
+      maxHealsReceived = mkOption {
+        type = types.ints.positive;
+        default = 100000;
+        description = "players need less heals received than this to be included in the stats";
+      };
+
+      maxKillStreak = mkOption {
+        type = types.ints.positive;
+        default = 20;
+        description = "kill streaks need to be shorter than this to be included in the stats";
+      };
+
+      minPlayTimePercent = mkOption {
+        type = types.ints.between 0 100;
+        default = 25;
+        description = "players need to play at least this percentage of a log to be included in the player stats and ratings";
+      };
+    };
+
     logLevel = mkOption {
       type = types.str;
       default = "info,sqlx=warn";
@@ -42,11 +302,15 @@ in {
       wantedBy = ["multi-user.target"];
       environment = {
         RUST_LOG = cfg.logLevel;
+        CONFIG = configFile;
       };
 
       serviceConfig = {
         EnvironmentFile = [cfg.databaseUrlFile cfg.rawDatabaseUrlFile];
         ExecStart = "${cfg.package}/bin/log-normalizer";
+        Type = "notify";
+        NotifyAccess = "main";
+        WatchdogSec = mkIf (cfg.watchdogSec != null) cfg.watchdogSec;
         Restart = "on-failure";
         User = cfg.user;
         PrivateTmp = true;
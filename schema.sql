@@ -4,18 +4,57 @@ CREATE TYPE team AS ENUM ('blue', 'red', 'other');
 
 CREATE TYPE class_type AS ENUM ('scout', 'soldier', 'pyro', 'demoman', 'heavyweapons', 'engineer', 'medic', 'sniper', 'spy', 'unknown');
 
-CREATE TYPE game_mode AS ENUM ('ultiduo', '4v4', '6v6', '7v7', '9v9', 'other');
+CREATE TYPE game_mode AS ENUM ('ultiduo', '2v2', '4v4', '5v5', '6v6', '7v7', 'prolander', '9v9', '12v12', 'other');
 
-CREATE TYPE map_type AS ENUM ('stopwatch', 'cp', 'koth', 'ctf', 'ultiduo', 'bball', 'other');
+CREATE TYPE map_type AS ENUM ('stopwatch', 'cp', 'koth', 'ctf', 'ultiduo', 'bball', 'payload_race', 'passtime',
+    'player_destruction', 'arena', 'medieval', 'special_delivery', 'training', 'other');
 
 CREATE TYPE event_type AS ENUM ('charge', 'pointcap', 'medic_death', 'round_win');
 
 CREATE TYPE medigun AS ENUM ('medigun', 'kritzkrieg', 'quickfix', 'vaccinator');
 
-CREATE OR REPLACE FUNCTION clean_map_name(map TEXT)
-    RETURNS TEXT AS $$
-SELECT regexp_replace(replace(map, 'workshop/', ''), '((_(a|b|beta|u|r|v|rc|final|comptf|ugc|f)?[0-9]*[a-z]?)?(_(a|b|beta|u|r|v|rc|final|comptf|ugc|f)?[0-9]*[a-z]?(_nb[0-9]*)?)|([0-9]+[a-z]?))(\.[a-z0-9]+)?$', '', 'g');
-$$ LANGUAGE SQL IMMUTABLE;
+CREATE TYPE round_end AS ENUM ('cap', 'time_limit', 'stalemate', 'forfeit', 'truncated');
+
+CREATE TYPE weapon_slot AS ENUM ('primary', 'secondary', 'melee', 'pda', 'building', 'other');
+
+CREATE TYPE pass_phase AS ENUM ('upgrade', 'normalize');
+
+-- limits for logs and players to be included in the stats, as last set from the normalizer config
+CREATE TABLE validity_thresholds (
+    id                  BOOL                    PRIMARY KEY DEFAULT true CHECK (id),
+    min_length          INTEGER                 NOT NULL,
+    max_length          INTEGER                 NOT NULL,
+    max_kills           INTEGER                 NOT NULL,
+    max_deaths          INTEGER                 NOT NULL,
+    max_class_damage    INTEGER                 NOT NULL,
+    max_damage_taken    INTEGER                 NOT NULL,
+    max_heals_received  INTEGER                 NOT NULL,
+    max_kill_streak     INTEGER                 NOT NULL,
+    min_play_time_percent INTEGER               NOT NULL
+);
+
+INSERT INTO validity_thresholds(min_length, max_length, max_kills, max_deaths, max_class_damage, max_damage_taken,
+                                max_heals_received, max_kill_streak, min_play_time_percent)
+    VALUES (60, 3600, 100, 100, 50000, 100000, 100000, 20, 25);
+
+-- where the last pass stopped when the normalizer was shut down, removed once a pass completes
+CREATE TABLE normalizer_checkpoint (
+    id              BOOL                        PRIMARY KEY DEFAULT true CHECK (id),
+    phase           pass_phase                  NOT NULL,
+    next_id         INTEGER                     NOT NULL,
+    target_version  SMALLINT                    NOT NULL,
+    stopped_at      TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT now()
+);
+
+-- logs that failed to normalize or upgrade after all retries, set retry to try them again in the next pass
+CREATE TABLE failed_logs (
+    id              INTEGER                     PRIMARY KEY,
+    phase           pass_phase                  NOT NULL,
+    attempts        INTEGER                     NOT NULL,
+    error           TEXT                        NOT NULL,
+    failed_at       TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT now(),
+    retry           BOOL                        NOT NULL DEFAULT false
+);
 
 CREATE TABLE logs (
     id              INTEGER                     PRIMARY KEY,
@@ -23,15 +62,26 @@ CREATE TABLE logs (
     blue_score      INTEGER                     NOT NULL,
     length          INTEGER                     NOT NULL,
     game_mode       game_mode                   NOT NULL,
+    game_mode_confidence REAL                   NOT NULL,
     map             TEXT                        NOT NULL,
-    clean_map       TEXT GENERATED ALWAYS AS (clean_map_name(map)) STORED,
+    clean_map       TEXT                        NOT NULL,
     type            map_type                    NOT NULL,
     date            TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    uploader        BIGINT                      NOT NULL,
+    title           TEXT                        NOT NULL,
+    has_accuracy    BOOL                        NOT NULL,
+    has_weapon_damage BOOL                      NOT NULL,
     winner          team GENERATED ALWAYS AS (CASE WHEN red_score > blue_score THEN 'red'::team WHEN blue_score > red_score THEN 'blue'::team ELSE 'other'::team END) STORED,
     version         SMALLINT                    NOT NULL,
-    is_valid        BOOL GENERATED ALWAYS AS (
-            length > 60 AND length < 3600 AND clean_map_name(map) != '' AND game_mode != 'other'
-    ) STORED
+    merged_from     INTEGER[]                   NOT NULL DEFAULT '{}',
+    merged_into     INTEGER,
+    duplicate_of    INTEGER,
+    rated           BOOL                        NOT NULL DEFAULT false,
+    -- set when the validity thresholds changed since the log was validated
+    revalidate      BOOL                        NOT NULL DEFAULT false,
+    -- bitmask of the validity rules the log failed
+    invalid_reasons INTEGER                     NOT NULL,
+    is_valid        BOOL GENERATED ALWAYS AS (invalid_reasons = 0) STORED
 );
 
 CREATE INDEX logs_map_idx
@@ -52,12 +102,82 @@ CREATE INDEX logs_date_idx
 CREATE INDEX logs_version_idx
     ON logs USING BTREE (version);
 
+CREATE INDEX logs_uploader_idx
+    ON logs USING BTREE (uploader);
+
+CREATE INDEX logs_merged_into_idx
+    ON logs USING BTREE (merged_into);
+
+CREATE INDEX logs_rated_date_idx
+    ON logs USING BTREE (rated, date, id);
+
+CREATE INDEX logs_revalidate_idx
+    ON logs USING BTREE (id) WHERE revalidate;
+
+CREATE TABLE matches (
+    id              SERIAL                      PRIMARY KEY,
+    team_a_score    INTEGER                     NOT NULL DEFAULT 0,
+    team_b_score    INTEGER                     NOT NULL DEFAULT 0,
+    team_a_wins     INTEGER                     NOT NULL DEFAULT 0,
+    team_b_wins     INTEGER                     NOT NULL DEFAULT 0,
+    start_date      TIMESTAMP WITHOUT TIME ZONE,
+    end_date        TIMESTAMP WITHOUT TIME ZONE
+);
+
+CREATE TABLE match_logs (
+    match_id        INTEGER                     NOT NULL REFERENCES matches(id),
+    log_id          INTEGER                     NOT NULL REFERENCES logs(id),
+    team_a          team                        NOT NULL
+);
+
+CREATE INDEX match_logs_match_id_idx
+    ON match_logs USING BTREE (match_id);
+
+CREATE UNIQUE INDEX match_logs_log_id_idx
+    ON match_logs USING BTREE (log_id);
+
+CREATE TABLE teams (
+    id              SERIAL                      PRIMARY KEY,
+    roster          BIGINT[]                    NOT NULL,
+    first_seen      TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    last_seen       TIMESTAMP WITHOUT TIME ZONE NOT NULL
+);
+
+CREATE INDEX teams_roster_idx
+    ON teams USING GIN (roster);
+
+CREATE TABLE log_teams (
+    log_id          INTEGER                     NOT NULL REFERENCES logs(id),
+    team            team                        NOT NULL,
+    team_id         INTEGER                     NOT NULL REFERENCES teams(id)
+);
+
+CREATE UNIQUE INDEX log_teams_log_id_team_idx
+    ON log_teams USING BTREE (log_id, team);
+
+CREATE INDEX log_teams_team_id_idx
+    ON log_teams USING BTREE (team_id);
+
+CREATE VIEW team_head_to_head AS
+    SELECT
+        team.team_id, opponent.team_id AS opponent_id,
+        count(*) AS count,
+        count(*) FILTER (WHERE logs.winner = team.team) AS wins,
+        count(*) FILTER (WHERE logs.winner = opponent.team) AS losses,
+        max(logs.date) AS last_played
+    FROM log_teams team
+    INNER JOIN log_teams opponent ON opponent.log_id = team.log_id AND opponent.team != team.team
+    INNER JOIN logs ON logs.id = team.log_id
+    WHERE logs.is_valid
+    GROUP BY team.team_id, opponent.team_id;
+
 CREATE TABLE rounds (
     id              SERIAL                      PRIMARY KEY,
     round           INTEGER                     NOT NULL,
     log_id          INTEGER                     NOT NULL REFERENCES logs(id),
     length          INTEGER                     NOT NULL,
     winner          team                        NOT NULL,
+    end_reason      round_end                   NOT NULL,
     first_cap       team                        NOT NULL,
     red_score       INTEGER                     NOT NULL,
     blue_score      INTEGER                     NOT NULL,
@@ -81,6 +201,9 @@ CREATE INDEX rounds_winner_idx
 CREATE INDEX rounds_first_cap_idx
     ON rounds USING BTREE (first_cap);
 
+CREATE INDEX rounds_end_reason_idx
+    ON rounds USING BTREE (end_reason);
+
 CREATE TABLE events_charge (
     id              BIGSERIAL                   PRIMARY KEY,
     round_id        INTEGER                     NOT NULL REFERENCES rounds(id),
@@ -146,59 +269,33 @@ CREATE TABLE events_round_win (
 CREATE UNIQUE INDEX events_round_win_round_id_idx
     ON events_round_win USING BTREE (round_id);
 
-CREATE OR REPLACE FUNCTION team_is_winner(log_id INTEGER, team team) RETURNS BOOL AS $$
-DECLARE
-    is_winner BOOLEAN;
-BEGIN
-    SELECT team = winner into is_winner FROM logs WHERE id = log_id;
-    RETURN is_winner;
-END; $$
-    LANGUAGE PLPGSQL IMMUTABLE;
-
-CREATE OR REPLACE FUNCTION get_game_mode(log_id INTEGER) RETURNS game_mode AS $$
-DECLARE
-    result game_mode;
-BEGIN
-    SELECT game_mode into result FROM logs WHERE id = log_id;
-    RETURN result;
-END; $$
-    LANGUAGE PLPGSQL IMMUTABLE;
-
-CREATE OR REPLACE FUNCTION get_clean_map(log_id INTEGER) RETURNS TEXT AS $$
-DECLARE
-    result TEXT;
-BEGIN
-    SELECT clean_map into result FROM logs WHERE id = log_id;
-    RETURN result;
-END; $$
-    LANGUAGE PLPGSQL IMMUTABLE;
-
-CREATE OR REPLACE FUNCTION get_date(log_id INTEGER) RETURNS TIMESTAMP WITHOUT TIME ZONE AS $$
-DECLARE
-    result TIMESTAMP WITHOUT TIME ZONE;
-BEGIN
-    SELECT date into result FROM logs WHERE id = log_id;
-    RETURN result;
-END; $$
-    LANGUAGE PLPGSQL IMMUTABLE;
-
-CREATE OR REPLACE FUNCTION get_length(log_id INTEGER) RETURNS INTEGER AS $$
-DECLARE
-    result INTEGER;
-BEGIN
-    SELECT length into result FROM logs WHERE id = log_id;
-    RETURN result;
-END; $$
-    LANGUAGE PLPGSQL IMMUTABLE;
-
-CREATE OR REPLACE FUNCTION is_log_valid(log_id INTEGER) RETURNS BOOL AS $$
-DECLARE
-    result BOOL;
-BEGIN
-    SELECT is_valid into result FROM logs WHERE id = log_id;
-    RETURN result;
-END; $$
-    LANGUAGE PLPGSQL IMMUTABLE;
+CREATE TABLE stopwatch_pairs (
+    id              SERIAL                      PRIMARY KEY,
+    log_id          INTEGER                     NOT NULL REFERENCES logs(id),
+    pair            INTEGER                     NOT NULL,
+    first_points    INTEGER                     NOT NULL,
+    first_time      INTEGER                     NOT NULL,
+    second_points   INTEGER,
+    second_time     INTEGER,
+    winner          team                        NOT NULL
+);
+
+CREATE UNIQUE INDEX stopwatch_pairs_log_id_pair_idx
+    ON stopwatch_pairs USING BTREE (log_id, pair);
+
+CREATE TABLE normalization_corrections (
+    id              BIGSERIAL                   PRIMARY KEY,
+    log_id          INTEGER                     NOT NULL REFERENCES logs(id),
+    pass            TEXT                        NOT NULL,
+    round           INTEGER,
+    description     TEXT                        NOT NULL
+);
+
+CREATE INDEX normalization_corrections_log_id_idx
+    ON normalization_corrections USING BTREE (log_id);
+
+CREATE INDEX normalization_corrections_pass_idx
+    ON normalization_corrections USING BTREE (pass);
 
 CREATE TABLE players (
     id              BIGSERIAL                   PRIMARY KEY,
@@ -242,15 +339,18 @@ CREATE TABLE players (
     medic_deaths    INTEGER                     NOT NULL,
     sniper_deaths   INTEGER                     NOT NULL,
     spy_deaths      INTEGER                     NOT NULL,
-    is_winner       BOOL GENERATED ALWAYS AS (team_is_winner(log_id, team)) STORED,
-    game_mode       game_mode GENERATED ALWAYS AS (get_game_mode(log_id)) STORED,
-    clean_map       TEXT GENERATED ALWAYS AS (get_clean_map(log_id)) STORED,
-    date            TIMESTAMP WITHOUT TIME ZONE GENERATED ALWAYS AS (get_date(log_id)) STORED,
-    length          INTEGER GENERATED ALWAYS AS (get_length(log_id)) STORED,
-    is_valid        BOOL GENERATED ALWAYS AS (
-        is_log_valid(log_id) AND damage_taken < 100000 AND heals_received < 100000 AND
-        kills < 100 AND deaths < 100 AND get_game_mode(log_id) != 'other'
-    ) STORED
+    time            INTEGER                     NOT NULL,
+    is_starter      BOOL                        NOT NULL,
+    -- played at least validity_thresholds.min_play_time_percent of the log
+    played_enough   BOOL                        NOT NULL,
+    invalid_reasons INTEGER                     NOT NULL,
+    -- copied from the log, kept in sync by the normalizer
+    is_winner       BOOL                        NOT NULL,
+    game_mode       game_mode                   NOT NULL,
+    clean_map       TEXT                        NOT NULL,
+    date            TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    length          INTEGER                     NOT NULL,
+    is_valid        BOOL GENERATED ALWAYS AS (invalid_reasons = 0) STORED
 );
 
 CREATE INDEX players_log_id_idx
@@ -283,14 +383,8 @@ CREATE INDEX players_year_idx
 CREATE INDEX players_is_valid_idx
     ON players USING BTREE (is_valid);
 
-CREATE OR REPLACE FUNCTION is_player_valid(player_id BIGINT) RETURNS BOOL AS $$
-DECLARE
-    result BOOL;
-BEGIN
-    SELECT is_valid into result FROM players WHERE id = player_id;
-    RETURN result;
-END; $$
-    LANGUAGE PLPGSQL IMMUTABLE;
+CREATE INDEX players_is_starter_idx
+    ON players USING BTREE (is_starter);
 
 CREATE TABLE class_stats (
     id              BIGSERIAL                   PRIMARY KEY,
@@ -301,9 +395,8 @@ CREATE TABLE class_stats (
     deaths          INTEGER                     NOT NULL,
     assists         INTEGER                     NOT NULL,
     dmg             INTEGER                     NOT NULL,
-    is_valid        BOOL GENERATED ALWAYS AS (
-        is_player_valid(player_id) AND kills < 100 AND deaths < 100 AND dmg < 50000 AND type != 'unknown' AND time > 0
-    ) STORED
+    invalid_reasons INTEGER                     NOT NULL,
+    is_valid        BOOL GENERATED ALWAYS AS (invalid_reasons = 0) STORED
 );
 
 CREATE INDEX class_stats_player_id_idx
@@ -331,9 +424,30 @@ CREATE INDEX player_weapon_stats_class_stat_id_idx
 CREATE UNIQUE INDEX player_weapon_stats_class_stat_id_weapon_idx
     ON player_weapon_stats USING BTREE (class_stat_id, weapon);
 
-CREATE MATERIALIZED VIEW player_stats AS
+CREATE TABLE weapons (
+    id              TEXT                        PRIMARY KEY,
+    name            TEXT                        NOT NULL,
+    class           class_type,
+    slot            weapon_slot                 NOT NULL,
+    stock           BOOL                        NOT NULL
+);
+
+-- logs that used weapons that aren't in the weapon registry
+CREATE TABLE unknown_weapon_logs (
+    name            TEXT                        NOT NULL,
+    log_id          INTEGER                     NOT NULL,
+    PRIMARY KEY (name, log_id)
+);
+
+CREATE VIEW unknown_weapons AS
+    SELECT name, count(*)::INTEGER as count, max(log_id) as last_log_id
+    FROM unknown_weapon_logs
+    GROUP BY name;
+
+-- the contribution of each log to the player stats, the player_stats table is the sum over all logs
+CREATE VIEW player_stats_contributions AS
     SELECT
-        game_mode, clean_map, extract(year from date)::INT as year,
+        log_id, game_mode, clean_map, extract(year from date)::INT as year,
             extract(month from date)::INT as month,
             class_stats.type as class,
             sum(class_stats.dmg) as damage,
@@ -341,17 +455,36 @@ CREATE MATERIALIZED VIEW player_stats AS
             sum(class_stats.deaths) as deaths,
             sum(class_stats.assists) as assists,
             sum(class_stats.time) as time,
-            sum(players.heals_received * (length / time)) as heals_received,
-            sum(players.damage_taken * (length / time)) as damage_taken,
+            sum(players.heals_received * (length / class_stats.time)) as heals_received,
+            sum(players.damage_taken * (length / class_stats.time)) as damage_taken,
             count(*) as count,
-            sum(is_winner::INTEGER) as wins,
+            coalesce(sum(is_winner::INTEGER), 0) as wins,
             steam_id
         FROM players
         INNER JOIN class_stats ON players.id = class_stats.player_id
-        WHERE class_stats.is_valid
-        GROUP BY game_mode, clean_map, extract(year from date)::INT, extract(month from date)::INT,
+        WHERE class_stats.is_valid AND players.played_enough
+        GROUP BY log_id, game_mode, clean_map, extract(year from date)::INT, extract(month from date)::INT,
                  class_stats.type, steam_id;
 
+CREATE TABLE player_stats (
+    game_mode       game_mode                   NOT NULL,
+    clean_map       TEXT                        NOT NULL,
+    year            INTEGER                     NOT NULL,
+    month           INTEGER                     NOT NULL,
+    class           class_type                  NOT NULL,
+    damage          BIGINT                      NOT NULL,
+    kills           BIGINT                      NOT NULL,
+    deaths          BIGINT                      NOT NULL,
+    assists         BIGINT                      NOT NULL,
+    time            BIGINT                      NOT NULL,
+    heals_received  BIGINT                      NOT NULL,
+    damage_taken    BIGINT                      NOT NULL,
+    count           BIGINT                      NOT NULL,
+    wins            BIGINT                      NOT NULL,
+    steam_id        BIGINT                      NOT NULL,
+    PRIMARY KEY (game_mode, clean_map, year, month, steam_id, class)
+);
+
 CREATE INDEX player_stats_steam_id_idx
     ON player_stats USING BTREE (steam_id);
 
@@ -364,13 +497,44 @@ CREATE INDEX player_stats_class_idx
 CREATE INDEX player_stats_date_idx
     ON player_stats USING BTREE (year, month);
 
-CREATE UNIQUE INDEX player_stats_unique_idx
-    ON player_stats USING BTREE (game_mode, clean_map, year, month, steam_id, class);
+CREATE MATERIALIZED VIEW weapon_stats AS
+    SELECT
+        weapon, class_stats.type as class, players.game_mode,
+            extract(year from players.date)::INT as year,
+            extract(month from players.date)::INT as month,
+            sum(player_weapon_stats.kills) as kills,
+            sum(player_weapon_stats.shots) as shots,
+            sum(player_weapon_stats.hits) as hits,
+            sum(player_weapon_stats.dmg) as damage,
+            sum(player_weapon_stats.hits) FILTER (WHERE player_weapon_stats.shots > 0)::REAL
+                / NULLIF(sum(player_weapon_stats.shots), 0) as accuracy,
+            sum(player_weapon_stats.dmg) FILTER (WHERE player_weapon_stats.shots > 0)::REAL
+                / NULLIF(sum(player_weapon_stats.shots), 0) as damage_per_shot,
+            sum(player_weapon_stats.kills)::REAL / NULLIF(sum(sum(player_weapon_stats.kills)) OVER (
+                PARTITION BY class_stats.type, players.game_mode,
+                    extract(year from players.date)::INT, extract(month from players.date)::INT
+            ), 0) as kill_share,
+            count(*) as count
+        FROM player_weapon_stats
+        INNER JOIN class_stats ON class_stats.id = player_weapon_stats.class_stat_id
+        INNER JOIN players ON players.id = class_stats.player_id
+        INNER JOIN logs ON logs.id = players.log_id
+        WHERE class_stats.is_valid AND logs.has_accuracy AND logs.has_weapon_damage
+        GROUP BY weapon, class_stats.type, players.game_mode,
+                 extract(year from players.date)::INT, extract(month from players.date)::INT;
+
+CREATE UNIQUE INDEX weapon_stats_unique_idx
+    ON weapon_stats USING BTREE (weapon, class, game_mode, year, month);
+
+CREATE INDEX weapon_stats_class_idx
+    ON weapon_stats USING BTREE (class, game_mode);
 
 CREATE MATERIALIZED VIEW player_names AS
     SELECT
-        steam_id, name, sum(length) as TIME, count(*) AS count
+        steam_id, name, sum(players.length) as TIME, count(*) AS count
     FROM players
+    INNER JOIN logs ON logs.id = players.log_id
+    WHERE logs.duplicate_of IS NULL AND logs.merged_into IS NULL
     GROUP BY steam_id, name;
 
 CREATE INDEX player_names_steam_id_idx
@@ -403,11 +567,11 @@ CREATE TABLE kill_streaks (
     steam_id        BIGINT                      NOT NULL,
     time            INTEGER                     NOT NULL,
     streak          INTEGER                     NOT NULL,
-    game_mode       game_mode GENERATED ALWAYS AS (get_game_mode(log_id)) STORED,
-    clean_map       TEXT GENERATED ALWAYS AS (get_clean_map(log_id)) STORED,
-    is_valid        BOOL GENERATED ALWAYS AS (
-        is_log_valid(log_id) AND streak < 20
-    ) STORED
+    -- copied from the log, kept in sync by the normalizer
+    game_mode       game_mode                   NOT NULL,
+    clean_map       TEXT                        NOT NULL,
+    invalid_reasons INTEGER                     NOT NULL,
+    is_valid        BOOL GENERATED ALWAYS AS (invalid_reasons = 0) STORED
 );
 
 CREATE INDEX kill_streaks_id_idx
@@ -423,4 +587,33 @@ CREATE INDEX kill_streaks_steam_id_streak_idx
     ON kill_streaks USING BTREE (steam_id, streak);
 
 CREATE INDEX kill_streaks_steam_id_streak_valid_idx
-    ON kill_streaks USING BTREE (steam_id, is_valid, streak);
\ No newline at end of file
+    ON kill_streaks USING BTREE (steam_id, is_valid, streak);
+
+CREATE TABLE player_ratings (
+    steam_id        BIGINT                      NOT NULL,
+    game_mode       game_mode                   NOT NULL,
+    class           class_type                  NOT NULL,
+    rating          DOUBLE PRECISION            NOT NULL,
+    games           INTEGER                     NOT NULL,
+    last_log_id     INTEGER                     NOT NULL REFERENCES logs(id),
+    PRIMARY KEY (steam_id, game_mode, class)
+);
+
+CREATE INDEX player_ratings_game_mode_class_rating_idx
+    ON player_ratings USING BTREE (game_mode, class, rating);
+
+CREATE TABLE rating_history (
+    id              BIGSERIAL                   PRIMARY KEY,
+    log_id          INTEGER                     NOT NULL REFERENCES logs(id),
+    steam_id        BIGINT                      NOT NULL,
+    game_mode       game_mode                   NOT NULL,
+    class           class_type                  NOT NULL,
+    rating_before   DOUBLE PRECISION            NOT NULL,
+    rating_after    DOUBLE PRECISION            NOT NULL
+);
+
+CREATE INDEX rating_history_log_id_idx
+    ON rating_history USING BTREE (log_id);
+
+CREATE INDEX rating_history_steam_id_idx
+    ON rating_history USING BTREE (steam_id, game_mode, class);
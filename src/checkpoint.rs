@@ -0,0 +1,88 @@
+use sqlx::PgPool;
+use tracing::instrument;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "pass_phase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum PassPhase {
+    Upgrade,
+    Normalize,
+}
+
+/// Where a pass stopped when the normalizer was shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub phase: PassPhase,
+    /// First log that wasn't processed
+    pub next_id: i32,
+    pub target_version: i16,
+}
+
+impl Checkpoint {
+    /// The first log to upgrade when resuming a pass that upgrades to `target_version` from `old`
+    pub fn upgrade_start(checkpoint: Option<Self>, old: i32, target_version: i16) -> i32 {
+        match checkpoint {
+            Some(Checkpoint {
+                phase: PassPhase::Upgrade,
+                next_id,
+                target_version: checkpoint_version,
+            }) if checkpoint_version == target_version => old.max(next_id),
+            _ => old,
+        }
+    }
+}
+
+#[instrument(skip(pool))]
+pub async fn store_checkpoint(pool: &PgPool, checkpoint: Checkpoint) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO normalizer_checkpoint(phase, next_id, target_version) VALUES($1, $2, $3) \
+        ON CONFLICT (id) DO UPDATE SET phase = EXCLUDED.phase, next_id = EXCLUDED.next_id, \
+            target_version = EXCLUDED.target_version, stopped_at = now()",
+        checkpoint.phase as PassPhase,
+        checkpoint.next_id,
+        checkpoint.target_version
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn load_checkpoint(pool: &PgPool) -> Result<Option<Checkpoint>, sqlx::Error> {
+    sqlx::query_as!(
+        Checkpoint,
+        r#"SELECT phase AS "phase: PassPhase", next_id, target_version FROM normalizer_checkpoint"#
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn clear_checkpoint(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM normalizer_checkpoint")
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_start() {
+        let checkpoint = Checkpoint {
+            phase: PassPhase::Upgrade,
+            next_id: 100,
+            target_version: 17,
+        };
+        assert_eq!(100, Checkpoint::upgrade_start(Some(checkpoint), 10, 17));
+        assert_eq!(150, Checkpoint::upgrade_start(Some(checkpoint), 150, 17));
+        // a different target version needs all old logs to be upgraded again
+        assert_eq!(10, Checkpoint::upgrade_start(Some(checkpoint), 10, 16));
+        let checkpoint = Checkpoint {
+            phase: PassPhase::Normalize,
+            ..checkpoint
+        };
+        assert_eq!(10, Checkpoint::upgrade_start(Some(checkpoint), 10, 17));
+        assert_eq!(10, Checkpoint::upgrade_start(None, 10, 17));
+    }
+}
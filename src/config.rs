@@ -0,0 +1,476 @@
+use anyhow::{bail, Context, Error};
+use serde::Deserialize;
+use std::env::VarError;
+use std::fs;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings for the normalizer, loaded from the file in `CONFIG` with overrides from the environment
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Seconds between passes when no new logs are announced
+    pub poll_interval: u64,
+    pub pool_size: u32,
+    pub raw_pool_size: u32,
+    /// Data version that old logs are upgraded to
+    pub target_version: i16,
+    pub map_catalog: Option<String>,
+    pub weapon_registry: Option<String>,
+    /// Share of a roster that needs to be in a team to count as that team
+    pub team_roster_overlap: f32,
+    pub raw_log_channel: String,
+    pub refresh_views: bool,
+    /// Minimum seconds between refreshes of the materialized views
+    pub view_refresh_interval: u64,
+    /// Weapon damage at or above this is treated as a broken log and ignored
+    pub max_weapon_damage: i64,
+    /// Address to serve the Prometheus metrics and health checks on, disabled if not set
+    pub http_address: Option<SocketAddr>,
+    /// Seconds a single log or view refresh can take before the normalizer is reported as stuck
+    pub stall_timeout: u64,
+    /// Attempts for a single log before it's moved to the failed logs
+    pub retry_attempts: u32,
+    /// Seconds before the first retry of a failed log, doubling with every attempt
+    pub retry_delay: u64,
+    /// Maximum seconds between the uploads of two copies of the same log
+    pub duplicate_window: i64,
+    /// Minimum share of each team that needs to be in both copies of the same log
+    pub duplicate_roster_overlap: f32,
+    /// Maximum difference in a team's kills per round between two copies of the same log
+    pub duplicate_kill_difference: i32,
+    pub matches: MatchConfig,
+    pub merge: MergeConfig,
+    pub validity: ValidityConfig,
+}
+
+/// Limits for grouping logs into matches
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchConfig {
+    /// Maximum seconds between the uploads of two logs of the same match
+    pub max_time_gap: i64,
+    /// Minimum share of a team that needs to be in both logs
+    pub min_roster_overlap: f32,
+    /// Minimum similarity score for two logs to be grouped into the same match
+    pub min_score: f32,
+}
+
+/// Limits for merging logs split by server crashes or map restarts
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MergeConfig {
+    /// Maximum seconds between the end of one part and the start of the next
+    pub max_gap: i64,
+    /// Minimum share of each team that needs to be in both parts
+    pub min_roster_overlap: f32,
+    /// Parts at least this many seconds long are only merged if their last round was cut off
+    pub full_game_length: u32,
+}
+
+/// Limits for logs and players to be included in the stats, the maximums are exclusive
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidityConfig {
+    pub min_length: i32,
+    pub max_length: i32,
+    pub max_kills: i32,
+    pub max_deaths: i32,
+    pub max_class_damage: i32,
+    pub max_damage_taken: i32,
+    pub max_heals_received: i32,
+    pub max_kill_streak: i32,
+    /// Percentage of the log a player needs to play to count for the player stats and ratings
+    pub min_play_time_percent: i32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            poll_interval: 15 * 60,
+            pool_size: 2,
+            raw_pool_size: 2,
+            target_version: crate::VERSION,
+            map_catalog: None,
+            weapon_registry: None,
+            team_roster_overlap: 4.0 / 6.0,
+            raw_log_channel: "logs_raw".into(),
+            refresh_views: true,
+            view_refresh_interval: 0,
+            max_weapon_damage: 100_000,
+            http_address: None,
+            stall_timeout: 10 * 60,
+            retry_attempts: 3,
+            retry_delay: 2,
+            duplicate_window: 2 * 60 * 60,
+            duplicate_roster_overlap: 0.8,
+            duplicate_kill_difference: 2,
+            matches: MatchConfig::default(),
+            merge: MergeConfig::default(),
+            validity: ValidityConfig::default(),
+        }
+    }
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        MatchConfig {
+            max_time_gap: 4 * 60 * 60,
+            min_roster_overlap: 0.5,
+            min_score: 0.6,
+        }
+    }
+}
+
+impl Default for MergeConfig {
+    fn default() -> Self {
+        MergeConfig {
+            max_gap: 15 * 60,
+            min_roster_overlap: 0.8,
+            full_game_length: 30 * 60,
+        }
+    }
+}
+
+impl Default for ValidityConfig {
+    fn default() -> Self {
+        ValidityConfig {
+            min_length: 60,
+            max_length: 3600,
+            max_kills: 100,
+            max_deaths: 100,
+            max_class_damage: 50_000,
+            max_damage_taken: 100_000,
+            max_heals_received: 100_000,
+            max_kill_streak: 20,
+            min_play_time_percent: 25,
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        toml::from_str(content).context("Failed to parse config")
+    }
+
+    /// Load the config file from `CONFIG` if set, apply the environment overrides and validate the result
+    pub fn from_env() -> Result<Self, Error> {
+        let mut config = match env_var("CONFIG")? {
+            Some(path) => {
+                let content = fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read config {}", path))?;
+                Self::parse(&content)?
+            }
+            None => Config::default(),
+        };
+        config.apply_overrides(env_var)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override settings with the matching variables
+    pub fn apply_overrides(
+        &mut self,
+        var: impl Fn(&str) -> Result<Option<String>, Error>,
+    ) -> Result<(), Error> {
+        fn set<T: FromStr>(
+            var: &impl Fn(&str) -> Result<Option<String>, Error>,
+            name: &str,
+            value: &mut T,
+        ) -> Result<(), Error>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            if let Some(raw) = var(name)? {
+                *value = raw
+                    .parse()
+                    .with_context(|| format!("Invalid {}: {}", name, raw))?;
+            }
+            Ok(())
+        }
+
+        set(&var, "POLL_INTERVAL", &mut self.poll_interval)?;
+        set(&var, "POOL_SIZE", &mut self.pool_size)?;
+        set(&var, "RAW_POOL_SIZE", &mut self.raw_pool_size)?;
+        set(&var, "TARGET_VERSION", &mut self.target_version)?;
+        if let Some(path) = var("MAP_CATALOG")? {
+            self.map_catalog = Some(path);
+        }
+        if let Some(path) = var("WEAPON_REGISTRY")? {
+            self.weapon_registry = Some(path);
+        }
+        set(&var, "TEAM_ROSTER_OVERLAP", &mut self.team_roster_overlap)?;
+        set(&var, "RAW_LOG_CHANNEL", &mut self.raw_log_channel)?;
+        set(&var, "REFRESH_VIEWS", &mut self.refresh_views)?;
+        set(
+            &var,
+            "VIEW_REFRESH_INTERVAL",
+            &mut self.view_refresh_interval,
+        )?;
+        set(&var, "MAX_WEAPON_DAMAGE", &mut self.max_weapon_damage)?;
+        if let Some(address) = var("HTTP_ADDRESS")? {
+            self.http_address = Some(
+                address
+                    .parse()
+                    .with_context(|| format!("Invalid HTTP_ADDRESS: {}", address))?,
+            );
+        }
+        set(&var, "STALL_TIMEOUT", &mut self.stall_timeout)?;
+        set(&var, "RETRY_ATTEMPTS", &mut self.retry_attempts)?;
+        set(&var, "RETRY_DELAY", &mut self.retry_delay)?;
+        set(&var, "DUPLICATE_WINDOW", &mut self.duplicate_window)?;
+        set(
+            &var,
+            "DUPLICATE_ROSTER_OVERLAP",
+            &mut self.duplicate_roster_overlap,
+        )?;
+        set(
+            &var,
+            "DUPLICATE_KILL_DIFFERENCE",
+            &mut self.duplicate_kill_difference,
+        )?;
+
+        let matches = &mut self.matches;
+        set(&var, "MATCH_MAX_TIME_GAP", &mut matches.max_time_gap)?;
+        set(
+            &var,
+            "MATCH_MIN_ROSTER_OVERLAP",
+            &mut matches.min_roster_overlap,
+        )?;
+        set(&var, "MATCH_MIN_SCORE", &mut matches.min_score)?;
+
+        let merge = &mut self.merge;
+        set(&var, "MERGE_MAX_GAP", &mut merge.max_gap)?;
+        set(
+            &var,
+            "MERGE_MIN_ROSTER_OVERLAP",
+            &mut merge.min_roster_overlap,
+        )?;
+        set(&var, "MERGE_FULL_GAME_LENGTH", &mut merge.full_game_length)?;
+
+        let validity = &mut self.validity;
+        set(&var, "VALIDITY_MIN_LENGTH", &mut validity.min_length)?;
+        set(&var, "VALIDITY_MAX_LENGTH", &mut validity.max_length)?;
+        set(&var, "VALIDITY_MAX_KILLS", &mut validity.max_kills)?;
+        set(&var, "VALIDITY_MAX_DEATHS", &mut validity.max_deaths)?;
+        set(
+            &var,
+            "VALIDITY_MAX_CLASS_DAMAGE",
+            &mut validity.max_class_damage,
+        )?;
+        set(
+            &var,
+            "VALIDITY_MAX_DAMAGE_TAKEN",
+            &mut validity.max_damage_taken,
+        )?;
+        set(
+            &var,
+            "VALIDITY_MAX_HEALS_RECEIVED",
+            &mut validity.max_heals_received,
+        )?;
+        set(
+            &var,
+            "VALIDITY_MAX_KILL_STREAK",
+            &mut validity.max_kill_streak,
+        )?;
+        set(
+            &var,
+            "VALIDITY_MIN_PLAY_TIME_PERCENT",
+            &mut validity.min_play_time_percent,
+        )?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.poll_interval == 0 {
+            bail!("poll_interval needs to be at least 1 second");
+        }
+        if self.pool_size == 0 || self.raw_pool_size == 0 {
+            bail!("pool_size and raw_pool_size need to be at least 1");
+        }
+        if self.target_version < 1 || self.target_version > crate::VERSION {
+            bail!(
+                "target_version needs to be between 1 and {}",
+                crate::VERSION
+            );
+        }
+        if self.raw_log_channel.is_empty() {
+            bail!("raw_log_channel can't be empty");
+        }
+        if self.stall_timeout == 0 {
+            bail!("stall_timeout needs to be at least 1 second");
+        }
+        if self.retry_attempts == 0 {
+            bail!("retry_attempts needs to be at least 1");
+        }
+        if self.max_weapon_damage <= 0 {
+            bail!("max_weapon_damage needs to be positive");
+        }
+        if self.duplicate_window < 0 {
+            bail!("duplicate_window can't be negative");
+        }
+        if self.duplicate_kill_difference < 0 {
+            bail!("duplicate_kill_difference can't be negative");
+        }
+        if self.matches.max_time_gap <= 0 {
+            bail!("matches.max_time_gap needs to be positive");
+        }
+        if self.merge.max_gap < 0 {
+            bail!("merge.max_gap can't be negative");
+        }
+        for (name, value) in [
+            ("team_roster_overlap", self.team_roster_overlap),
+            ("duplicate_roster_overlap", self.duplicate_roster_overlap),
+            (
+                "matches.min_roster_overlap",
+                self.matches.min_roster_overlap,
+            ),
+            ("matches.min_score", self.matches.min_score),
+            ("merge.min_roster_overlap", self.merge.min_roster_overlap),
+        ] {
+            if !(value > 0.0 && value <= 1.0) {
+                bail!("{} needs to be between 0 and 1", name);
+            }
+        }
+        let validity = &self.validity;
+        if validity.min_length < 0 || validity.min_length >= validity.max_length {
+            bail!("validity.min_length needs to be positive and below validity.max_length");
+        }
+        if !(0..=100).contains(&validity.min_play_time_percent) {
+            bail!("validity.min_play_time_percent needs to be between 0 and 100");
+        }
+        for (name, value) in [
+            ("max_kills", validity.max_kills),
+            ("max_deaths", validity.max_deaths),
+            ("max_class_damage", validity.max_class_damage),
+            ("max_damage_taken", validity.max_damage_taken),
+            ("max_heals_received", validity.max_heals_received),
+            ("max_kill_streak", validity.max_kill_streak),
+        ] {
+            if value <= 0 {
+                bail!("validity.{} needs to be positive", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the config used by the normalizer, can only be done once
+    pub fn init(config: Config) {
+        if CONFIG.set(config).is_err() {
+            panic!("config already initialized");
+        }
+    }
+
+    /// The config set by [`Config::init`], or the defaults if none has been set
+    pub fn global() -> &'static Config {
+        CONFIG.get_or_init(Config::default)
+    }
+}
+
+/// Read an environment variable, only a variable that isn't set at all counts as missing
+fn env_var(name: &str) -> Result<Option<String>, Error> {
+    match dotenvy::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(dotenvy::Error::EnvVar(VarError::NotPresent)) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            poll_interval = 60
+            pool_size = 4
+            http_address = "127.0.0.1:9100"
+
+            [validity]
+            max_kills = 150
+            "#,
+        )
+        .unwrap();
+        assert_eq!(60, config.poll_interval);
+        assert_eq!(4, config.pool_size);
+        assert_eq!(2, config.raw_pool_size);
+        assert_eq!(Some(([127, 0, 0, 1], 9100).into()), config.http_address);
+        assert_eq!(150, config.validity.max_kills);
+        assert_eq!(100, config.validity.max_deaths);
+        assert!(config.validate().is_ok());
+
+        assert!(Config::parse("pool_sise = 4").is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let env: HashMap<&str, &str> = [
+            ("POLL_INTERVAL", "30"),
+            ("MAP_CATALOG", "/etc/maps.toml"),
+            ("VALIDITY_MAX_LENGTH", "7200"),
+            ("MERGE_MAX_GAP", "600"),
+        ]
+        .iter()
+        .copied()
+        .collect();
+        let mut config = Config::default();
+        config
+            .apply_overrides(|name| Ok(env.get(name).map(|value| value.to_string())))
+            .unwrap();
+        assert_eq!(30, config.poll_interval);
+        assert_eq!(Some("/etc/maps.toml".into()), config.map_catalog);
+        assert_eq!(7200, config.validity.max_length);
+        assert_eq!(600, config.merge.max_gap);
+
+        assert!(config
+            .apply_overrides(|name| Ok((name == "POOL_SIZE").then(|| "two".into())))
+            .is_err());
+        assert!(config
+            .apply_overrides(|name| match name {
+                "POOL_SIZE" => Err(VarError::NotUnicode("\u{fffd}".into()).into()),
+                _ => Ok(None),
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+
+        let config = Config {
+            team_roster_overlap: 1.5,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            matches: MatchConfig {
+                min_score: 0.0,
+                ..MatchConfig::default()
+            },
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            target_version: crate::VERSION + 1,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            validity: ValidityConfig {
+                min_length: 4000,
+                ..ValidityConfig::default()
+            },
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -11,7 +11,18 @@ pub enum TeamId {
     Other,
 }
 
-#[derive(Debug, Clone, Copy, sqlx::Type, Deserialize, Serialize, Eq, PartialEq)]
+impl TeamId {
+    /// The team playing against this one, `Other` has no opponent
+    pub fn opponent(self) -> Self {
+        match self {
+            TeamId::Blue => TeamId::Red,
+            TeamId::Red => TeamId::Blue,
+            TeamId::Other => TeamId::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, sqlx::Type, Deserialize, Serialize, Hash, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 #[sqlx(rename_all = "lowercase")]
 #[sqlx(type_name = "class_type")]
@@ -29,33 +40,32 @@ pub enum Class {
     Unknown,
 }
 
-#[derive(Debug, Clone, Copy, sqlx::Type, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, sqlx::Type, Hash, Eq, PartialEq)]
 #[sqlx(type_name = "game_mode")]
 pub enum GameMode {
     #[sqlx(rename = "ultiduo")]
     UltiDuo,
+    #[sqlx(rename = "2v2")]
+    Twos,
     #[sqlx(rename = "4v4")]
     Fours,
+    #[sqlx(rename = "5v5")]
+    Fives,
     #[sqlx(rename = "6v6")]
     Sixes,
     #[sqlx(rename = "7v7")]
     Sevens,
+    /// 7v7 with a limit of one player per class
+    #[sqlx(rename = "prolander")]
+    Prolander,
     #[sqlx(rename = "9v9")]
     Highlander,
+    #[sqlx(rename = "12v12")]
+    Casual,
     #[sqlx(rename = "other")]
     Other,
 }
 
-#[derive(Debug, Clone, Copy, sqlx::Type, Deserialize, Serialize, Eq, PartialEq)]
-pub enum EventType {
-    Charge,
-    PointCap,
-    MedicDeath,
-    RoundWin,
-    #[serde(other)]
-    Other,
-}
-
 #[derive(Debug, Clone, Copy, sqlx::Type, Deserialize, Serialize, Hash, Eq, PartialEq, Default)]
 #[serde(rename_all = "lowercase")]
 #[sqlx(rename_all = "lowercase")]
@@ -63,12 +73,14 @@ pub enum Medigun {
     KritzKrieg,
     QuickFix,
     Vaccinator,
-    #[serde(other)]
+    #[serde(other, rename = "medigun")]
+    #[sqlx(rename = "medigun")]
     #[default]
-    Medigun,
+    Stock,
 }
 
-#[derive(Debug, Clone, Copy, sqlx::Type, Eq, PartialEq, Default)]
+#[derive(Debug, Clone, Copy, sqlx::Type, Deserialize, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
 #[sqlx(rename_all = "lowercase")]
 #[sqlx(type_name = "map_type")]
 pub enum MapType {
@@ -78,6 +90,51 @@ pub enum MapType {
     Ctf,
     UltiDuo,
     BBall,
+    #[serde(rename = "payload_race")]
+    #[sqlx(rename = "payload_race")]
+    PayloadRace,
+    Passtime,
+    #[serde(rename = "player_destruction")]
+    #[sqlx(rename = "player_destruction")]
+    PlayerDestruction,
+    Arena,
+    Medieval,
+    #[serde(rename = "special_delivery")]
+    #[sqlx(rename = "special_delivery")]
+    SpecialDelivery,
+    /// tr_ and mge_ training maps
+    Training,
     #[default]
     Other,
 }
+
+#[derive(Debug, Clone, Copy, sqlx::Type, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+#[sqlx(type_name = "weapon_slot")]
+pub enum WeaponSlot {
+    Primary,
+    Secondary,
+    Melee,
+    Pda,
+    Building,
+    /// Kills that aren't made with a weapon, such as reflects or environmental deaths
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, sqlx::Type, Eq, PartialEq, Default)]
+#[sqlx(rename_all = "snake_case")]
+#[sqlx(type_name = "round_end")]
+pub enum RoundEnd {
+    /// Round won by capturing the final point or completing the objective
+    Cap,
+    /// Round won by the defending team holding out until the round timer ran out
+    TimeLimit,
+    /// Round ended without a winner
+    Stalemate,
+    /// Round won without the winner completing the objective
+    Forfeit,
+    /// Log ended before the round was finished
+    #[default]
+    Truncated,
+}
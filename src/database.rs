@@ -1,27 +1,101 @@
-use crate::data::{Class, GameMode, MapType, Medigun, TeamId};
-use crate::normalized::NormalizedLog;
-use crate::raw::Event;
+use crate::config::{Config, ValidityConfig};
+use crate::data::{Class, GameMode, MapType, Medigun, RoundEnd, TeamId};
+use crate::duplicates::find_duplicate;
+use crate::matches::{group_log, remove_logs};
+use crate::normalized::{NormalizedLog, Round};
+use crate::raw::{Event, WeaponStat};
+use crate::rollup::{add_to_rollup, subtract_from_rollup};
+use crate::teams::assign_teams;
+use crate::validity::{InvalidReasons, LogValidity, Validator};
+use crate::weapons::{report_unknown, WeaponRegistry};
 use chrono::{DateTime, Utc};
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
 use std::collections::HashMap;
 use steamid_ng::SteamID;
 use tracing::instrument;
 
+/// Record the validity thresholds that logs are validated with, returns whether they changed
+///
+/// When they changed all stored logs are marked to be revalidated with the new thresholds
+#[instrument(skip(pool))]
+pub async fn store_validity_thresholds(
+    pool: &PgPool,
+    validity: &ValidityConfig,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query!(
+        "UPDATE validity_thresholds SET min_length = $1, max_length = $2, max_kills = $3, max_deaths = $4, \
+            max_class_damage = $5, max_damage_taken = $6, max_heals_received = $7, max_kill_streak = $8, \
+            min_play_time_percent = $9 \
+        WHERE (min_length, max_length, max_kills, max_deaths, max_class_damage, max_damage_taken, \
+            max_heals_received, max_kill_streak, min_play_time_percent) \
+            IS DISTINCT FROM ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        validity.min_length,
+        validity.max_length,
+        validity.max_kills,
+        validity.max_deaths,
+        validity.max_class_damage,
+        validity.max_damage_taken,
+        validity.max_heals_received,
+        validity.max_kill_streak,
+        validity.min_play_time_percent
+    )
+    .execute(&mut *tx)
+    .await?;
+    let changed = result.rows_affected() > 0;
+    if changed {
+        sqlx::query!("UPDATE logs SET revalidate = true")
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(changed)
+}
+
+/// Store a normalized log, `merged_from` lists the raw logs that were merged into it if it was split
+///
+/// Returns the rules the log failed
 #[instrument(skip(pool, log))]
-pub async fn store_log(pool: &PgPool, id: i32, log: &NormalizedLog) -> Result<(), sqlx::Error> {
+pub async fn store_log(
+    pool: &PgPool,
+    id: i32,
+    log: &NormalizedLog,
+    merged_from: &[i32],
+) -> Result<InvalidReasons, sqlx::Error> {
     let mut tx = pool.begin().await?;
+    let game_mode = log.detect_game_mode();
+    let duplicate_of = find_duplicate(&mut tx, id, log).await?;
+    let validity = Validator::new(&Config::global().validity).validate(
+        log,
+        game_mode.mode,
+        match duplicate_of {
+            Some(_) => InvalidReasons::DUPLICATE,
+            None => InvalidReasons::NONE,
+        },
+    );
+    let clean_map = log.info.clean_map();
+    let winner = log.winner();
     sqlx::query!(
-        "INSERT INTO logs(id, red_score, blue_score, length, game_mode, map, type, date, version)\
-            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        "INSERT INTO logs(id, red_score, blue_score, length, game_mode, game_mode_confidence, map, clean_map, type, date, uploader, title, version, merged_from, duplicate_of, has_accuracy, has_weapon_damage, invalid_reasons)\
+            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)",
         id,
         log.teams.red.score as i32,
         log.teams.blue.score as i32,
         log.info.total_length as i32,
-        log.game_mode() as GameMode,
+        game_mode.mode as GameMode,
+        game_mode.confidence,
         log.info.map,
+        clean_map,
         log.info.map_type() as MapType,
         log.info.date() as DateTime<Utc>,
-        2
+        u64::from(log.info.uploader.id) as i64,
+        log.info.title,
+        crate::VERSION,
+        merged_from,
+        duplicate_of,
+        log.info.has_accuracy,
+        log.info.has_weapon_damage,
+        validity.log.bits()
     )
     .execute(&mut *tx)
     .await?;
@@ -29,15 +103,16 @@ pub async fn store_log(pool: &PgPool, id: i32, log: &NormalizedLog) -> Result<()
     for (num, round) in log.rounds.iter().enumerate() {
         let round_id: i32 = sqlx::query!(
             r#"INSERT INTO rounds(
-                round, log_id, length, winner, first_cap, red_score, blue_score,
+                round, log_id, length, winner, end_reason, first_cap, red_score, blue_score,
                 red_kills, blue_kills, red_dmg, blue_dmg, red_ubers, blue_ubers
             )
-            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
             RETURNING id"#,
             num as i32,
             id,
             round.length as i32,
             round.winner.unwrap_or_default() as TeamId,
+            round.end as RoundEnd,
             round.first_cap as TeamId,
             round.team.red.score as i32,
             round.team.blue.score as i32,
@@ -52,107 +127,14 @@ pub async fn store_log(pool: &PgPool, id: i32, log: &NormalizedLog) -> Result<()
         .await?
         .id;
 
-        for event in &round.events {
-            match event {
-                Event::PointCap {
-                    time,
-                    team: Some(team),
-                    point,
-                } => {
-                    sqlx::query!(
-                        "INSERT INTO events_point_cap(round_id, time, team, point)\
-                        VALUES($1, $2, $3, $4)",
-                        round_id,
-                        *time as i32,
-                        *team as TeamId,
-                        *point as i32,
-                    )
-                    .execute(&mut *tx)
-                    .await?;
-                }
-                Event::RoundWin {
-                    time,
-                    team: Some(team),
-                } => {
-                    sqlx::query!(
-                        "INSERT INTO events_round_win(round_id, time, team)\
-                        VALUES($1, $2, $3)",
-                        round_id,
-                        *time as i32,
-                        *team as TeamId,
-                    )
-                    .execute(&mut *tx)
-                    .await?;
-                }
-                Event::MedicDeath {
-                    time,
-                    team: Some(team),
-                    steamid,
-                    killer,
-                } => {
-                    sqlx::query!(
-                        "INSERT INTO events_medic_death(round_id, time, team, steam_id, killer)\
-                            VALUES($1, $2, $3, $4, $5)",
-                        round_id,
-                        *time as i32,
-                        *team as TeamId,
-                        u64::from(*steamid) as i64,
-                        u64::from(*killer) as i64,
-                    )
-                    .execute(&mut *tx)
-                    .await?;
-                }
-                Event::Drop {
-                    time,
-                    steamid,
-                    team: Some(team),
-                } => {
-                    sqlx::query!(
-                        "INSERT INTO events_drop(round_id, time, team, steam_id)\
-                            VALUES($1, $2, $3, $4)",
-                        round_id,
-                        *time as i32,
-                        *team as TeamId,
-                        u64::from(*steamid) as i64,
-                    )
-                    .execute(&mut *tx)
-                    .await?;
-                }
-                Event::Charge {
-                    medigun,
-                    time,
-                    steamid,
-                    team: Some(team),
-                } => {
-                    sqlx::query!(
-                        "INSERT INTO events_charge(round_id, time, team, medigun, steam_id)\
-                            VALUES($1, $2, $3, $4, $5)",
-                        round_id,
-                        *time as i32,
-                        *team as TeamId,
-                        *medigun as Medigun,
-                        u64::from(*steamid) as i64,
-                    )
-                    .execute(&mut *tx)
-                    .await?;
-                }
-                _ => {}
-            }
-        }
+        store_events(&mut tx, round_id, round).await?;
     }
 
-    let mut heals_received: HashMap<SteamID, u32> = HashMap::new();
-    for heal_map in log.heal_spread.values() {
-        for (steam_id, heals) in heal_map {
-            heals_received
-                .entry(*steam_id)
-                .and_modify(|received| *received += heals)
-                .or_insert(*heals);
-        }
-    }
+    let heals_received = log.heals_received();
 
     for (steam_id, player) in &log.players {
         if let Some(team) = player.team {
+            let player_validity = &validity.players[steam_id];
             let kills = log.class_kills.get(steam_id).cloned().unwrap_or_default();
             let deaths = log.class_deaths.get(steam_id).cloned().unwrap_or_default();
             let player_id: i64 = sqlx::query!(
@@ -165,13 +147,16 @@ pub async fn store_log(pool: &PgPool, id: i32, log: &NormalizedLog) -> Result<()
                 scout_kills, soldier_kills, pyro_kills, demoman_kills,\
                 heavy_kills, engineer_kills, medic_kills, sniper_kills, spy_kills,
                 scout_deaths, soldier_deaths, pyro_deaths, demoman_deaths,\
-                heavy_deaths, engineer_deaths, medic_deaths, sniper_deaths, spy_deaths
+                heavy_deaths, engineer_deaths, medic_deaths, sniper_deaths, spy_deaths,\
+                time, is_starter, played_enough, invalid_reasons,\
+                is_winner, game_mode, clean_map, date, length
             )\
             VALUES(\
                 $1, $2, $3, $4, $5, $6, $7, $8, $9, $10,\
                 $11, $12, $13, $14, $15, $16, $17, $18, $19, $20,\
                 $21, $22, $23, $24, $25, $26, $27, $28, $29, $30,\
-                $31, $32, $33, $34, $35, $36, $37, $38, $39, $40\
+                $31, $32, $33, $34, $35, $36, $37, $38, $39, $40,\
+                $41, $42, $43, $44, $45, $46, $47, $48, $49\
             )\
             RETURNING id",
                 id as i32,
@@ -187,7 +172,7 @@ pub async fn store_log(pool: &PgPool, id: i32, log: &NormalizedLog) -> Result<()
                 player.ubers as i32,
                 player
                     .ubertypes
-                    .get(&Medigun::Medigun)
+                    .get(&Medigun::Stock)
                     .copied()
                     .unwrap_or_default() as i32,
                 player
@@ -230,6 +215,15 @@ pub async fn store_log(pool: &PgPool, id: i32, log: &NormalizedLog) -> Result<()
                 deaths.medic as i32,
                 deaths.sniper as i32,
                 deaths.spy as i32,
+                player.play_time() as i32,
+                log.is_starter(steam_id),
+                player_validity.played_enough,
+                player_validity.player.bits(),
+                team == winner,
+                game_mode.mode as GameMode,
+                clean_map,
+                log.info.date() as DateTime<Utc>,
+                log.info.total_length as i32,
             )
             .fetch_one(&mut *tx)
             .await?
@@ -238,8 +232,8 @@ pub async fn store_log(pool: &PgPool, id: i32, log: &NormalizedLog) -> Result<()
             for class in &player.class_stats {
                 if class.class != Class::Unknown {
                     let class_stat_id: i64 = sqlx::query!(
-                    "INSERT INTO class_stats(player_id, type, time, kills, deaths, assists, dmg)\
-                            VALUES($1, $2, $3, $4, $5, $6, $7)\
+                    "INSERT INTO class_stats(player_id, type, time, kills, deaths, assists, dmg, invalid_reasons)\
+                            VALUES($1, $2, $3, $4, $5, $6, $7, $8)\
                             RETURNING id",
                     player_id,
                     class.class as Class,
@@ -248,46 +242,56 @@ pub async fn store_log(pool: &PgPool, id: i32, log: &NormalizedLog) -> Result<()
                     class.deaths as i32,
                     class.assists as i32,
                     class.dmg as i32,
+                    player_validity.classes[&class.class].bits(),
                 )
                     .fetch_one(&mut *tx)
                     .await?
                     .id;
 
-                    for (weapon, stats) in &class.weapon {
-                        sqlx::query!(
-                            "INSERT INTO player_weapon_stats(class_stat_id, weapon, kills, shots, hits, dmg)\
-                                VALUES($1, $2, $3, $4, $5, $6)",
-                            class_stat_id,
-                            *weapon,
-                            stats.kills as i32,
-                            stats.shots as i32,
-                            stats.hits as i32,
-                            stats.dmg as i32,
-                        )
-                            .execute(&mut *tx)
-                            .await?;
-                    }
+                    store_weapon_stats(&mut tx, id, class_stat_id, &class.weapon).await?;
                 }
             }
         }
     }
 
-    for kill_streak in &log.kill_streaks {
-        sqlx::query!(
-            "INSERT INTO kill_streaks(log_id, steam_id, time, streak)\
-                VALUES($1, $2, $3, $4)",
-            id,
-            u64::from(kill_streak.steamid) as i64,
-            kill_streak.time,
-            kill_streak.streak,
-        )
-        .execute(&mut *tx)
-        .await?;
+    store_kill_streaks(&mut tx, id, log, &validity).await?;
+    store_stopwatch_pairs(&mut tx, id, log).await?;
+    store_corrections(&mut tx, id, log).await?;
+    mark_merged(&mut tx, id, merged_from).await?;
+    if duplicate_of.is_none() {
+        group_log(&mut tx, id, log).await?;
+        assign_teams(&mut tx, id, log).await?;
     }
+    add_to_rollup(&mut tx, &[id]).await?;
 
     tx.commit().await?;
 
-    Ok(())
+    Ok(validity.log)
+}
+
+/// Validate a stored log again with the current thresholds
+///
+/// Returns the rules the log failed
+#[instrument(skip(pool, log))]
+pub async fn revalidate(
+    pool: &PgPool,
+    id: i32,
+    log: &NormalizedLog,
+) -> Result<InvalidReasons, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    subtract_from_rollup(&mut tx, &[id]).await?;
+    let validity = validate_stored(&mut tx, id, log).await?;
+    store_validity(&mut tx, id, log, &validity).await?;
+    add_to_rollup(&mut tx, &[id]).await?;
+    // invalid logs aren't rated
+    sqlx::query!(
+        "UPDATE logs SET revalidate = false, rated = false WHERE id = $1",
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(validity.log)
 }
 
 #[instrument(skip(pool, log))]
@@ -299,31 +303,693 @@ pub async fn upgrade(
     to: i16,
 ) -> Result<(), sqlx::Error> {
     let mut tx = pool.begin().await?;
+    // the upgrade can change anything about the log, so its stats are added back at the end
+    subtract_from_rollup(&mut tx, &[id]).await?;
 
     if from <= 1 && to >= 2 {
-        for kill_streak in &log.kill_streaks {
+        let validity = validate_stored(&mut tx, id, log).await?;
+        store_kill_streaks(&mut tx, id, log, &validity).await?;
+    }
+
+    if from <= 2 && to >= 3 {
+        for (num, round) in log.rounds.iter().enumerate() {
             sqlx::query!(
-                "INSERT INTO kill_streaks(log_id, steam_id, time, streak)\
-                VALUES($1, $2, $3, $4)",
+                "UPDATE rounds SET end_reason = $1 WHERE log_id = $2 AND round = $3",
+                round.end as RoundEnd,
                 id,
-                u64::from(kill_streak.steamid) as i64,
-                kill_streak.time,
-                kill_streak.streak,
+                num as i32,
             )
             .execute(&mut *tx)
             .await?;
         }
     }
 
-    sqlx::query!("UPDATE logs SET version = $1 WHERE id = $2", to, id)
+    if from <= 3 && to >= 4 {
+        sqlx::query!(
+            "DELETE FROM normalization_corrections WHERE log_id = $1",
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        store_corrections(&mut tx, id, log).await?;
+    }
+
+    if from <= 4 && to >= 5 {
+        sqlx::query!(
+            "UPDATE logs SET red_score = $1, blue_score = $2 WHERE id = $3",
+            log.teams.red.score as i32,
+            log.teams.blue.score as i32,
+            id
+        )
         .execute(&mut *tx)
         .await?;
+        sync_log_columns(&mut tx, &[id]).await?;
+        store_stopwatch_pairs(&mut tx, id, log).await?;
+    }
+
+    if from <= 5 && to >= 6 {
+        sqlx::query!(
+            "UPDATE logs SET clean_map = $1, type = $2 WHERE id = $3",
+            log.info.clean_map(),
+            log.info.map_type() as MapType,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sync_log_columns(&mut tx, &[id]).await?;
+    }
+
+    if from <= 6 && to >= 7 {
+        sqlx::query!(
+            "UPDATE logs SET type = $1 WHERE id = $2",
+            log.info.map_type() as MapType,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if from <= 7 && to >= 8 {
+        let game_mode = log.detect_game_mode();
+        sqlx::query!(
+            "UPDATE logs SET game_mode = $1, game_mode_confidence = $2 WHERE id = $3",
+            game_mode.mode as GameMode,
+            game_mode.confidence,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sync_log_columns(&mut tx, &[id]).await?;
+    }
+
+    if from <= 8 && to >= 9 {
+        // only the logs that are detected as one of the game modes added in this version change
+        let game_mode = log.detect_game_mode();
+        if matches!(
+            game_mode.mode,
+            GameMode::Twos | GameMode::Fives | GameMode::Prolander | GameMode::Casual
+        ) {
+            sqlx::query!(
+                "UPDATE logs SET game_mode = $1, game_mode_confidence = $2 WHERE id = $3",
+                game_mode.mode as GameMode,
+                game_mode.confidence,
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sync_log_columns(&mut tx, &[id]).await?;
+        }
+    }
+
+    if from <= 9 && to >= 10 {
+        for (steam_id, player) in &log.players {
+            sqlx::query!(
+                "UPDATE players SET time = $1, is_starter = $2 WHERE log_id = $3 AND steam_id = $4",
+                player.play_time() as i32,
+                log.is_starter(steam_id),
+                id,
+                u64::from(*steam_id) as i64,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    if from <= 10 && to >= 11 {
+        sqlx::query!(
+            "UPDATE logs SET uploader = $1, title = $2 WHERE id = $3",
+            u64::from(log.info.uploader.id) as i64,
+            log.info.title,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        group_log(&mut tx, id, log).await?;
+    }
+
+    if from <= 11 && to >= 12 {
+        let duplicate_of = find_duplicate(&mut tx, id, log).await?;
+        sqlx::query!(
+            "UPDATE logs SET duplicate_of = $1 WHERE id = $2",
+            duplicate_of,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if duplicate_of.is_some() {
+            invalidate_logs(&mut tx, &[id], InvalidReasons::DUPLICATE).await?;
+            remove_logs(&mut tx, &[id]).await?;
+        }
+    }
+
+    if from <= 12 && to >= 13 {
+        let valid = sqlx::query!(
+            "SELECT duplicate_of IS NULL AND merged_into IS NULL AS \"valid!\" FROM logs WHERE id = $1",
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .valid;
+        if valid {
+            assign_teams(&mut tx, id, log).await?;
+        }
+    }
+
+    if from <= 13 && to >= 14 {
+        sqlx::query!(
+            "UPDATE logs SET has_accuracy = $1, has_weapon_damage = $2 WHERE id = $3",
+            log.info.has_accuracy,
+            log.info.has_weapon_damage,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if from <= 14 && to >= 15 {
+        let class_stats = sqlx::query!(
+            "SELECT class_stats.id, players.steam_id, class_stats.type AS \"class: Class\" \
+            FROM class_stats INNER JOIN players ON class_stats.player_id = players.id \
+            WHERE players.log_id = $1",
+            id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for class_stat in class_stats {
+            sqlx::query!(
+                "DELETE FROM player_weapon_stats WHERE class_stat_id = $1",
+                class_stat.id
+            )
+            .execute(&mut *tx)
+            .await?;
+            let steam_id = SteamID::from(class_stat.steam_id as u64);
+            let weapons = log
+                .players
+                .get(&steam_id)
+                .and_then(|player| {
+                    player
+                        .class_stats
+                        .iter()
+                        .find(|stats| stats.class == class_stat.class)
+                })
+                .map(|stats| &stats.weapon);
+            if let Some(weapons) = weapons {
+                store_weapon_stats(&mut tx, id, class_stat.id, weapons).await?;
+            }
+        }
+    }
+
+    if from <= 15 && to >= 16 {
+        let validity = validate_stored(&mut tx, id, log).await?;
+        store_validity(&mut tx, id, log, &validity).await?;
+    }
+
+    if from <= 16 && to >= 17 {
+        sync_log_columns(&mut tx, &[id]).await?;
+    }
+
+    if from <= 17 && to >= 18 {
+        let validity = validate_stored(&mut tx, id, log).await?;
+        store_validity(&mut tx, id, log, &validity).await?;
+    }
+
+    if from <= 18 && to >= 19 {
+        // rounds with reset event times are detected by their first event instead of their last
+        for (num, round) in log.rounds.iter().enumerate() {
+            let round_id = sqlx::query!(
+                "SELECT id FROM rounds WHERE log_id = $1 AND round = $2",
+                id,
+                num as i32
+            )
+            .fetch_one(&mut *tx)
+            .await?
+            .id;
+            sqlx::query!("DELETE FROM events_point_cap WHERE round_id = $1", round_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!("DELETE FROM events_round_win WHERE round_id = $1", round_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!(
+                "DELETE FROM events_medic_death WHERE round_id = $1",
+                round_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!("DELETE FROM events_drop WHERE round_id = $1", round_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!("DELETE FROM events_charge WHERE round_id = $1", round_id)
+                .execute(&mut *tx)
+                .await?;
+            store_events(&mut tx, round_id, round).await?;
+        }
+        sqlx::query!(
+            "DELETE FROM normalization_corrections WHERE log_id = $1",
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        store_corrections(&mut tx, id, log).await?;
+        // the stopwatch times and scores are computed from the event times
+        sqlx::query!("DELETE FROM stopwatch_pairs WHERE log_id = $1", id)
+            .execute(&mut *tx)
+            .await?;
+        store_stopwatch_pairs(&mut tx, id, log).await?;
+        sqlx::query!(
+            "UPDATE logs SET red_score = $1, blue_score = $2 WHERE id = $3",
+            log.teams.red.score as i32,
+            log.teams.blue.score as i32,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sync_log_columns(&mut tx, &[id]).await?;
+    }
+
+    if from <= 19 && to >= 20 {
+        // stopwatch pairs are won by the team that actually attacked, equal halves are a tie
+        sqlx::query!("DELETE FROM stopwatch_pairs WHERE log_id = $1", id)
+            .execute(&mut *tx)
+            .await?;
+        store_stopwatch_pairs(&mut tx, id, log).await?;
+        sqlx::query!(
+            "UPDATE logs SET red_score = $1, blue_score = $2 WHERE id = $3",
+            log.teams.red.score as i32,
+            log.teams.blue.score as i32,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sync_log_columns(&mut tx, &[id]).await?;
+    }
+
+    if from <= 20 && to >= 21 {
+        // copies with a late joining player or a few missed kills are duplicates too
+        let stored = sqlx::query!("SELECT duplicate_of FROM logs WHERE id = $1", id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if stored.duplicate_of.is_none() {
+            if let Some(duplicate_of) = find_duplicate(&mut tx, id, log).await? {
+                sqlx::query!(
+                    "UPDATE logs SET duplicate_of = $1 WHERE id = $2",
+                    duplicate_of,
+                    id
+                )
+                .execute(&mut *tx)
+                .await?;
+                invalidate_logs(&mut tx, &[id], InvalidReasons::DUPLICATE).await?;
+                remove_logs(&mut tx, &[id]).await?;
+            }
+        }
+    }
+
+    add_to_rollup(&mut tx, &[id]).await?;
+    // the upgrade can change anything the rating depends on
+    sqlx::query!(
+        "UPDATE logs SET version = $1, rated = false WHERE id = $2",
+        to,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
 
     tx.commit().await?;
 
     Ok(())
 }
 
+/// Store the weapon stats of a class under the canonical weapon names, unknown weapons are kept under their own name
+async fn store_weapon_stats(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    class_stat_id: i64,
+    weapons: &HashMap<String, WeaponStat>,
+) -> Result<(), sqlx::Error> {
+    let registry = WeaponRegistry::global();
+    let mut normalized: HashMap<&str, WeaponStat> = HashMap::with_capacity(weapons.len());
+    for (weapon, stats) in weapons {
+        let name = match registry.lookup(weapon) {
+            Some(info) => info.id.as_str(),
+            None => {
+                report_unknown(weapon);
+                // logs are stored again by upgrades and use the weapon for multiple players, so count them once
+                sqlx::query!(
+                    "INSERT INTO unknown_weapon_logs(name, log_id) VALUES($1, $2) ON CONFLICT DO NOTHING",
+                    weapon,
+                    id
+                )
+                .execute(&mut **tx)
+                .await?;
+                weapon.as_str()
+            }
+        };
+        match normalized.get_mut(name) {
+            Some(existing) => existing.add(stats),
+            None => {
+                normalized.insert(name, stats.clone());
+            }
+        }
+    }
+
+    for (weapon, stats) in normalized {
+        sqlx::query!(
+            "INSERT INTO player_weapon_stats(class_stat_id, weapon, kills, shots, hits, dmg)\
+                VALUES($1, $2, $3, $4, $5, $6)",
+            class_stat_id,
+            weapon,
+            stats.kills as i32,
+            stats.shots as i32,
+            stats.hits as i32,
+            stats.dmg as i32,
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Mark the earlier parts of a merged log so they are excluded from the stats
+async fn mark_merged(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    merged_from: &[i32],
+) -> Result<(), sqlx::Error> {
+    let parts: Vec<i32> = merged_from
+        .iter()
+        .copied()
+        .filter(|part| *part != id)
+        .collect();
+    if parts.is_empty() {
+        return Ok(());
+    }
+    subtract_from_rollup(tx, &parts).await?;
+    sqlx::query!(
+        "UPDATE logs SET merged_into = $1 WHERE id = ANY($2)",
+        id,
+        &parts
+    )
+    .execute(&mut **tx)
+    .await?;
+    invalidate_logs(tx, &parts, InvalidReasons::MERGED).await?;
+    add_to_rollup(tx, &parts).await?;
+    remove_logs(tx, &parts).await?;
+    sqlx::query!("DELETE FROM log_teams WHERE log_id = ANY($1)", &parts)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Mark the logs and everything in them as invalid
+async fn invalidate_logs(
+    tx: &mut Transaction<'_, Postgres>,
+    ids: &[i32],
+    reasons: InvalidReasons,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE logs SET invalid_reasons = invalid_reasons | $2, rated = false WHERE id = ANY($1)",
+        ids,
+        reasons.bits()
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE players SET invalid_reasons = invalid_reasons | $2 WHERE log_id = ANY($1)",
+        ids,
+        InvalidReasons::INVALID_LOG.bits()
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE class_stats SET invalid_reasons = invalid_reasons | $2 \
+            WHERE player_id IN (SELECT id FROM players WHERE log_id = ANY($1))",
+        ids,
+        InvalidReasons::INVALID_PLAYER.bits()
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE kill_streaks SET invalid_reasons = invalid_reasons | $2 WHERE log_id = ANY($1)",
+        ids,
+        InvalidReasons::INVALID_LOG.bits()
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Validate a stored log, including the reasons that are only known from the database
+async fn validate_stored(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    log: &NormalizedLog,
+) -> Result<LogValidity, sqlx::Error> {
+    let stored = sqlx::query!(
+        "SELECT game_mode as \"game_mode: GameMode\", merged_into, duplicate_of FROM logs WHERE id = $1",
+        id
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    let mut extra = InvalidReasons::NONE;
+    if stored.merged_into.is_some() {
+        extra |= InvalidReasons::MERGED;
+    }
+    if stored.duplicate_of.is_some() {
+        extra |= InvalidReasons::DUPLICATE;
+    }
+    Ok(Validator::new(&Config::global().validity).validate(log, stored.game_mode, extra))
+}
+
+/// Update the validity of a stored log and everything in it
+async fn store_validity(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    log: &NormalizedLog,
+    validity: &LogValidity,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE logs SET invalid_reasons = $1 WHERE id = $2",
+        validity.log.bits(),
+        id
+    )
+    .execute(&mut **tx)
+    .await?;
+    for (steam_id, player) in &validity.players {
+        let steam_id = u64::from(*steam_id) as i64;
+        sqlx::query!(
+            "UPDATE players SET invalid_reasons = $1, played_enough = $2 WHERE log_id = $3 AND steam_id = $4",
+            player.player.bits(),
+            player.played_enough,
+            id,
+            steam_id
+        )
+        .execute(&mut **tx)
+        .await?;
+        for (class, reasons) in &player.classes {
+            sqlx::query!(
+                "UPDATE class_stats SET invalid_reasons = $1 \
+                    WHERE type = $2 AND player_id = (SELECT id FROM players WHERE log_id = $3 AND steam_id = $4)",
+                reasons.bits(),
+                *class as Class,
+                id,
+                steam_id
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+    }
+    sqlx::query!("DELETE FROM kill_streaks WHERE log_id = $1", id)
+        .execute(&mut **tx)
+        .await?;
+    store_kill_streaks(tx, id, log, validity).await
+}
+
+/// Store the events of a stored round
+async fn store_events(
+    tx: &mut Transaction<'_, Postgres>,
+    round_id: i32,
+    round: &Round,
+) -> Result<(), sqlx::Error> {
+    for event in &round.events {
+        match event {
+            Event::PointCap {
+                time,
+                team: Some(team),
+                point,
+            } => {
+                sqlx::query!(
+                    "INSERT INTO events_point_cap(round_id, time, team, point)\
+                    VALUES($1, $2, $3, $4)",
+                    round_id,
+                    *time as i32,
+                    *team as TeamId,
+                    *point as i32,
+                )
+                .execute(&mut **tx)
+                .await?;
+            }
+            Event::RoundWin {
+                time,
+                team: Some(team),
+            } => {
+                sqlx::query!(
+                    "INSERT INTO events_round_win(round_id, time, team)\
+                    VALUES($1, $2, $3)",
+                    round_id,
+                    *time as i32,
+                    *team as TeamId,
+                )
+                .execute(&mut **tx)
+                .await?;
+            }
+            Event::MedicDeath {
+                time,
+                team: Some(team),
+                steamid,
+                killer,
+            } => {
+                sqlx::query!(
+                    "INSERT INTO events_medic_death(round_id, time, team, steam_id, killer)\
+                        VALUES($1, $2, $3, $4, $5)",
+                    round_id,
+                    *time as i32,
+                    *team as TeamId,
+                    u64::from(*steamid) as i64,
+                    u64::from(*killer) as i64,
+                )
+                .execute(&mut **tx)
+                .await?;
+            }
+            Event::Drop {
+                time,
+                steamid,
+                team: Some(team),
+            } => {
+                sqlx::query!(
+                    "INSERT INTO events_drop(round_id, time, team, steam_id)\
+                        VALUES($1, $2, $3, $4)",
+                    round_id,
+                    *time as i32,
+                    *team as TeamId,
+                    u64::from(*steamid) as i64,
+                )
+                .execute(&mut **tx)
+                .await?;
+            }
+            Event::Charge {
+                medigun,
+                time,
+                steamid,
+                team: Some(team),
+            } => {
+                sqlx::query!(
+                    "INSERT INTO events_charge(round_id, time, team, medigun, steam_id)\
+                        VALUES($1, $2, $3, $4, $5)",
+                    round_id,
+                    *time as i32,
+                    *team as TeamId,
+                    *medigun as Medigun,
+                    u64::from(*steamid) as i64,
+                )
+                .execute(&mut **tx)
+                .await?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Copy the columns of the logs that are duplicated into their players and kill streaks,
+/// needs to be done whenever those columns of a stored log change
+async fn sync_log_columns(
+    tx: &mut Transaction<'_, Postgres>,
+    ids: &[i32],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE players SET is_winner = players.team = logs.winner, game_mode = logs.game_mode, \
+            clean_map = logs.clean_map, date = logs.date, length = logs.length \
+        FROM logs WHERE players.log_id = logs.id AND logs.id = ANY($1)",
+        ids
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE kill_streaks SET game_mode = logs.game_mode, clean_map = logs.clean_map \
+        FROM logs WHERE kill_streaks.log_id = logs.id AND logs.id = ANY($1)",
+        ids
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Store the kill streaks of a log, the game mode and map are copied from the stored log
+async fn store_kill_streaks(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    log: &NormalizedLog,
+    validity: &LogValidity,
+) -> Result<(), sqlx::Error> {
+    for (kill_streak, reasons) in log.kill_streaks.iter().zip(&validity.kill_streaks) {
+        sqlx::query!(
+            "INSERT INTO kill_streaks(log_id, steam_id, time, streak, invalid_reasons, game_mode, clean_map) \
+                SELECT $1, $2, $3, $4, $5, game_mode, clean_map FROM logs WHERE id = $1",
+            id,
+            u64::from(kill_streak.steamid) as i64,
+            kill_streak.time,
+            kill_streak.streak,
+            reasons.bits()
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn store_stopwatch_pairs(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    log: &NormalizedLog,
+) -> Result<(), sqlx::Error> {
+    for (num, pair) in log.stopwatch_pairs.iter().enumerate() {
+        sqlx::query!(
+            "INSERT INTO stopwatch_pairs(log_id, pair, first_points, first_time, second_points, second_time, winner)\
+                VALUES($1, $2, $3, $4, $5, $6, $7)",
+            id,
+            num as i32,
+            pair.first.points as i32,
+            pair.first.time as i32,
+            pair.second.as_ref().map(|half| half.points as i32),
+            pair.second.as_ref().map(|half| half.time as i32),
+            pair.winner as TeamId,
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn store_corrections(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    log: &NormalizedLog,
+) -> Result<(), sqlx::Error> {
+    for correction in &log.corrections {
+        sqlx::query!(
+            "INSERT INTO normalization_corrections(log_id, pass, round, description)\
+                VALUES($1, $2, $3, $4)",
+            id,
+            correction.pass,
+            correction.round.map(|round| round as i32),
+            correction.description,
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
 // macro_rules! insert_fields {
 //     ($table:ident, {
 //         $($($field:ident => $value:expr),)+
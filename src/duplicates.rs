@@ -0,0 +1,218 @@
+use crate::config::Config;
+use crate::data::TeamId;
+use crate::normalized::NormalizedLog;
+use chrono::Duration;
+use sqlx::{Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, instrument};
+
+/// The parts of a log that are (almost) the same for every upload of a match
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UploadSummary {
+    pub red: HashSet<i64>,
+    pub blue: HashSet<i64>,
+    pub rounds: Vec<RoundSummary>,
+}
+
+/// Score and kills of a round, kills can differ slightly between uploads when the logs start or stop at different moments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoundSummary {
+    pub red_score: i32,
+    pub blue_score: i32,
+    pub red_kills: i32,
+    pub blue_kills: i32,
+}
+
+impl UploadSummary {
+    pub fn new(log: &NormalizedLog) -> Self {
+        let roster = |team: TeamId| {
+            log.players
+                .iter()
+                .filter(|(_, player)| player.team == Some(team))
+                .map(|(steam_id, _)| u64::from(*steam_id) as i64)
+                .collect()
+        };
+        UploadSummary {
+            red: roster(TeamId::Red),
+            blue: roster(TeamId::Blue),
+            rounds: log
+                .rounds
+                .iter()
+                .map(|round| RoundSummary {
+                    red_score: round.team.red.score as i32,
+                    blue_score: round.team.blue.score as i32,
+                    red_kills: round.team.red.kills as i32,
+                    blue_kills: round.team.blue.kills as i32,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Share of the larger roster that is also in the other roster, so an extra player in either log lowers it
+fn roster_overlap(a: &HashSet<i64>, b: &HashSet<i64>) -> f32 {
+    let largest = a.len().max(b.len());
+    if largest == 0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f32 / largest as f32
+}
+
+/// Check if two logs are uploads of the same match, allowing for a late joining player or a few missed kills
+pub fn is_same_upload(
+    a: &UploadSummary,
+    b: &UploadSummary,
+    min_roster_overlap: f32,
+    max_kill_difference: i32,
+) -> bool {
+    if a.rounds.is_empty() || a.rounds.len() != b.rounds.len() {
+        return false;
+    }
+    let rounds_match = a.rounds.iter().zip(&b.rounds).all(|(a, b)| {
+        a.red_score == b.red_score
+            && a.blue_score == b.blue_score
+            && (a.red_kills - b.red_kills).abs() <= max_kill_difference
+            && (a.blue_kills - b.blue_kills).abs() <= max_kill_difference
+    });
+    rounds_match
+        && roster_overlap(&a.red, &b.red) >= min_roster_overlap
+        && roster_overlap(&a.blue, &b.blue) >= min_roster_overlap
+}
+
+/// Find the canonical log that this log is a duplicate of, the earliest log on the same map around the same time
+/// with (almost) the same rosters and round results
+#[instrument(skip(tx, log))]
+pub async fn find_duplicate(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    log: &NormalizedLog,
+) -> Result<Option<i32>, sqlx::Error> {
+    let config = Config::global();
+    let date = log.info.date().naive_utc();
+    let window = Duration::seconds(config.duplicate_window);
+    let ids: Vec<i32> = sqlx::query!(
+        "SELECT id FROM logs \
+        WHERE clean_map = $1 AND date BETWEEN $2 AND $3 AND id < $4 AND duplicate_of IS NULL \
+        ORDER BY id",
+        log.info.clean_map(),
+        date - window,
+        date + window,
+        id
+    )
+    .fetch_all(&mut **tx)
+    .await?
+    .into_iter()
+    .map(|row| row.id)
+    .collect();
+    if ids.is_empty() {
+        return Ok(None);
+    }
+
+    let mut candidates: HashMap<i32, UploadSummary> = HashMap::new();
+    let players = sqlx::query!(
+        r#"SELECT log_id, steam_id, team as "team: TeamId" FROM players WHERE log_id = ANY($1)"#,
+        &ids
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+    for player in players {
+        let candidate = candidates.entry(player.log_id).or_default();
+        match player.team {
+            TeamId::Red => candidate.red.insert(player.steam_id),
+            TeamId::Blue => candidate.blue.insert(player.steam_id),
+            TeamId::Other => false,
+        };
+    }
+    let rounds = sqlx::query!(
+        "SELECT log_id, red_score, blue_score, red_kills, blue_kills FROM rounds \
+        WHERE log_id = ANY($1) ORDER BY log_id, round",
+        &ids
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+    for round in rounds {
+        candidates
+            .entry(round.log_id)
+            .or_default()
+            .rounds
+            .push(RoundSummary {
+                red_score: round.red_score,
+                blue_score: round.blue_score,
+                red_kills: round.red_kills,
+                blue_kills: round.blue_kills,
+            });
+    }
+
+    let summary = UploadSummary::new(log);
+    let canonical = ids.into_iter().find(|id| {
+        candidates.get(id).is_some_and(|candidate| {
+            is_same_upload(
+                &summary,
+                candidate,
+                config.duplicate_roster_overlap,
+                config.duplicate_kill_difference,
+            )
+        })
+    });
+    if let Some(canonical) = canonical {
+        debug!(canonical, "found duplicate log");
+    }
+    Ok(canonical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use steamid_ng::SteamID;
+
+    fn parse(file: &str) -> NormalizedLog {
+        let content = fs::read_to_string(format!("tests/data/{}", file)).unwrap();
+        serde_json::from_str(&content).unwrap()
+    }
+
+    fn is_duplicate(a: &NormalizedLog, b: &NormalizedLog) -> bool {
+        is_same_upload(&UploadSummary::new(a), &UploadSummary::new(b), 0.8, 2)
+    }
+
+    #[test]
+    fn test_same_upload() {
+        let log = parse("3579548.json");
+
+        let mut other_upload = log.clone();
+        other_upload.info.title = "RED vs BLU".into();
+        other_upload.info.date += 120;
+        other_upload.info.uploader.id = SteamID::from(76561198000000001);
+        assert!(is_duplicate(&log, &other_upload));
+
+        assert!(!is_duplicate(&log, &parse("3578739.json")));
+
+        let mut different_score = log.clone();
+        different_score.rounds[0].team.red.score += 1;
+        assert!(!is_duplicate(&log, &different_score));
+    }
+
+    #[test]
+    fn test_almost_same_upload() {
+        let log = parse("3579548.json");
+
+        // the uploader's log started a bit later and missed a kill and a late joining player
+        let mut other_upload = log.clone();
+        other_upload.rounds[0].team.red.kills += 1;
+        other_upload.rounds[0].team.blue.kills -= 2;
+        let (_, player) = log
+            .players
+            .iter()
+            .find(|(_, player)| player.team == Some(TeamId::Red))
+            .unwrap();
+        other_upload
+            .players
+            .insert(SteamID::from(76561198000000001), player.clone());
+        assert!(is_duplicate(&log, &other_upload));
+        assert!(is_duplicate(&other_upload, &log));
+
+        let mut more_kills = log.clone();
+        more_kills.rounds[0].team.red.kills += 3;
+        assert!(!is_duplicate(&log, &more_kills));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::checkpoint::PassPhase;
+use crate::shutdown::Shutdown;
+use anyhow::Error;
+use sqlx::PgPool;
+use std::cmp::min;
+use std::future::Future;
+use tokio::time::{sleep, Duration};
+use tracing::{instrument, warn};
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// How often the work for a single log is attempted before it's given up on
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    attempts: u32,
+    delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(attempts: u32, delay: Duration) -> Self {
+        RetryPolicy { attempts, delay }
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Delay after the failed attempt, doubling with every attempt
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        min(self.delay.saturating_mul(factor), MAX_RETRY_DELAY)
+    }
+
+    /// Run the step until it succeeds or all attempts failed, returns early with the last error on shutdown
+    pub async fn run<F, Fut>(&self, id: i32, shutdown: &Shutdown, mut step: F) -> Result<(), Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        let mut shutdown = shutdown.clone();
+        let mut attempt = 1;
+        loop {
+            let error = match step().await {
+                Ok(()) => return Ok(()),
+                Err(error) if attempt >= self.attempts => return Err(error),
+                Err(error) => error,
+            };
+            let delay = self.delay(attempt);
+            warn!(
+                id,
+                attempt,
+                retry = debug(delay),
+                error = display(format_args!("{:#}", error)),
+                "failed to process log"
+            );
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = shutdown.wait() => return Err(error),
+            }
+            attempt += 1;
+        }
+    }
+}
+
+/// Whether the error is caused by losing the database connection, which isn't specific to a single log
+pub fn is_connection_error(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<sqlx::Error>(),
+            Some(
+                sqlx::Error::Io(_)
+                    | sqlx::Error::Tls(_)
+                    | sqlx::Error::PoolTimedOut
+                    | sqlx::Error::PoolClosed
+                    | sqlx::Error::WorkerCrashed
+            )
+        )
+    })
+}
+
+/// Move a log that failed all attempts to the failed logs with its full error chain
+#[instrument(skip(pool, error))]
+pub async fn store_failure(
+    pool: &PgPool,
+    id: i32,
+    phase: PassPhase,
+    attempts: u32,
+    error: &Error,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO failed_logs(id, phase, attempts, error) VALUES($1, $2, $3, $4) \
+        ON CONFLICT (id) DO UPDATE SET phase = EXCLUDED.phase, attempts = EXCLUDED.attempts, \
+            error = EXCLUDED.error, failed_at = now(), retry = false",
+        id,
+        phase as PassPhase,
+        attempts as i32,
+        format!("{:#}", error)
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Get the new logs that are marked to be retried, failed upgrades that are marked are handed back to the upgrade loop
+pub async fn take_retries(pool: &PgPool) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query!("DELETE FROM failed_logs WHERE retry AND phase = 'upgrade'")
+        .execute(pool)
+        .await?;
+    Ok(
+        sqlx::query!("SELECT id FROM failed_logs WHERE retry AND phase = 'normalize' ORDER BY id")
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| row.id)
+            .collect(),
+    )
+}
+
+pub async fn clear_failure(pool: &PgPool, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM failed_logs WHERE id = $1", id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::new(5, Duration::from_secs(2));
+        assert_eq!(Duration::from_secs(2), policy.delay(1));
+        assert_eq!(Duration::from_secs(4), policy.delay(2));
+        assert_eq!(Duration::from_secs(8), policy.delay(3));
+        assert_eq!(MAX_RETRY_DELAY, policy.delay(20));
+        assert_eq!(MAX_RETRY_DELAY, policy.delay(u32::MAX));
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let shutdown = Shutdown::listen().unwrap();
+        let policy = RetryPolicy::new(3, Duration::from_millis(1));
+
+        let mut calls = 0;
+        let result = policy
+            .run(1, &shutdown, || {
+                calls += 1;
+                let result = if calls < 2 {
+                    Err(anyhow!("failed"))
+                } else {
+                    Ok(())
+                };
+                async move { result }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(2, calls);
+
+        let mut calls = 0;
+        let result = policy
+            .run(1, &shutdown, || {
+                calls += 1;
+                async { Err(anyhow!("failed")) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(3, calls);
+    }
+
+    #[test]
+    fn test_is_connection_error() {
+        let error = Error::from(sqlx::Error::PoolTimedOut).context("Failed to store log");
+        assert!(is_connection_error(&error));
+
+        let error: Result<(), _> = Err(sqlx::Error::RowNotFound);
+        let error = error.context("Failed to store log").unwrap_err();
+        assert!(!is_connection_error(&error));
+        assert!(!is_connection_error(&anyhow!("failed parse raw log")));
+    }
+}
@@ -0,0 +1,253 @@
+use crate::data::{Class, GameMode, MapType, TeamId};
+use crate::normalized::NormalizedLog;
+use std::collections::HashMap;
+
+/// Detected game mode and how confident we are in the detection, from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GameModeDetection {
+    pub mode: GameMode,
+    pub confidence: f32,
+}
+
+#[derive(Debug, Default)]
+struct TeamComposition {
+    /// Sum of the play time of all players in the team
+    time: u32,
+    /// Number of players that played for at least half the match
+    core_players: usize,
+    class_time: HashMap<Class, u32>,
+}
+
+impl TeamComposition {
+    /// Average number of players on the team at any point in the match
+    fn size(&self, length: u32) -> f32 {
+        self.time as f32 / length as f32
+    }
+
+    /// Average number of players playing the class at any point in the match
+    fn class_presence(&self, class: Class, length: u32) -> f32 {
+        self.class_time.get(&class).copied().unwrap_or_default() as f32 / length as f32
+    }
+}
+
+/// Maximum average number of players on a class for a team to count as using a one-per-class limit
+const CLASS_LIMIT_PRESENCE: f32 = 1.2;
+
+pub fn detect_game_mode(log: &NormalizedLog) -> GameModeDetection {
+    if log.info.map_type() == MapType::UltiDuo {
+        return GameModeDetection {
+            mode: GameMode::UltiDuo,
+            confidence: 1.0,
+        };
+    }
+
+    let length = log.median_play_time();
+
+    if length == 0 {
+        return GameModeDetection {
+            mode: game_mode_from_player_count(log.players.len()),
+            confidence: 0.5,
+        };
+    }
+
+    let mut red = TeamComposition::default();
+    let mut blue = TeamComposition::default();
+    for player in log.players.values() {
+        let team = match player.team {
+            Some(TeamId::Red) => &mut red,
+            Some(TeamId::Blue) => &mut blue,
+            _ => continue,
+        };
+        let time = player.play_time();
+        team.time += time;
+        if time * 2 >= length {
+            team.core_players += 1;
+        }
+        for class in &player.class_stats {
+            *team.class_time.entry(class.class).or_default() += class.total_time;
+        }
+    }
+
+    let red_size = red.size(length).round() as usize;
+    let blue_size = blue.size(length).round() as usize;
+    let size = red_size.max(blue_size);
+    let class_limited = [&red, &blue].iter().all(|team| {
+        team.class_time
+            .keys()
+            .all(|class| team.class_presence(*class, length) <= CLASS_LIMIT_PRESENCE)
+    });
+    let mode = match size {
+        2 => GameMode::Twos,
+        4 => GameMode::Fours,
+        5 => GameMode::Fives,
+        6 => GameMode::Sixes,
+        7 if class_limited => GameMode::Prolander,
+        7 => GameMode::Sevens,
+        9 => GameMode::Highlander,
+        11..=13 => GameMode::Casual,
+        _ => GameMode::Other,
+    };
+
+    let mut confidence = 1.0;
+    for team in [&red, &blue] {
+        // players leaving without being replaced make the team size less certain
+        confidence *= 1.0 - (team.size(length) - size as f32).abs().min(1.0) / 2.0;
+        if team.core_players != size {
+            confidence *= 0.9;
+        }
+        if team.class_presence(Class::Medic, length) > 1.5 {
+            confidence *= 0.75;
+        }
+    }
+    if mode == GameMode::Highlander && !class_limited {
+        confidence *= 0.5;
+    }
+    if mode == GameMode::Twos && log.info.map_type() != MapType::BBall {
+        confidence *= 0.5;
+    }
+
+    GameModeDetection { mode, confidence }
+}
+
+/// Guess the game mode from the number of players, for logs without class play time
+///
+/// 14 players is as likely to be prolander as sixes with substitutes, so it's left undetected
+fn game_mode_from_player_count(count: usize) -> GameMode {
+    match count {
+        7..=9 => GameMode::Fours,
+        11..=13 => GameMode::Sixes,
+        17..=19 => GameMode::Highlander,
+        _ => GameMode::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use steamid_ng::SteamID;
+    use test_case::test_case;
+
+    fn parse(file: &str) -> NormalizedLog {
+        let content = fs::read_to_string(format!("tests/data/{}", file)).unwrap();
+        serde_json::from_str(&content).unwrap()
+    }
+
+    /// Replace the first player of the team for the last `time` seconds they played
+    fn add_substitute(log: &mut NormalizedLog, team: TeamId, steam_id: u64, time: u32) {
+        let player = log
+            .players
+            .values_mut()
+            .filter(|player| player.team == Some(team))
+            .find(|player| player.class_stats.len() == 1)
+            .unwrap();
+        player.class_stats[0].total_time -= time;
+        let mut substitute = player.clone();
+        substitute.class_stats[0].total_time = time;
+        log.players.insert(SteamID::from(steam_id), substitute);
+    }
+
+    /// Only keep one player with each of the given classes as main class per team
+    fn keep_classes(log: &mut NormalizedLog, classes: &[Class]) {
+        let mut kept = Vec::new();
+        log.players.retain(|_, player| {
+            let class = player
+                .class_stats
+                .iter()
+                .max_by_key(|class| class.total_time)
+                .map(|class| class.class);
+            match class {
+                Some(class)
+                    if classes.contains(&class) && !kept.contains(&(player.team, class)) =>
+                {
+                    kept.push((player.team, class));
+                    true
+                }
+                _ => false,
+            }
+        });
+    }
+
+    const PROLANDER_CLASSES: [Class; 7] = [
+        Class::Scout,
+        Class::Soldier,
+        Class::Pyro,
+        Class::Demoman,
+        Class::HeavyWeapons,
+        Class::Medic,
+        Class::Sniper,
+    ];
+
+    #[test_case("1.json", GameMode::Sixes)]
+    #[test_case("114840.json", GameMode::Casual)]
+    #[test_case("134389.json", GameMode::Highlander)]
+    #[test_case("550237.json", GameMode::Highlander)]
+    #[test_case("2522305.json", GameMode::Highlander)]
+    #[test_case("3578739.json", GameMode::Sixes)]
+    #[test_case("3579548.json", GameMode::Sixes)]
+    fn test_detect_game_mode(file: &str, expected: GameMode) {
+        let parsed = parse(file);
+
+        assert_eq!(expected, detect_game_mode(&parsed).mode);
+    }
+
+    #[test_case(8, GameMode::Fours)]
+    #[test_case(12, GameMode::Sixes)]
+    #[test_case(14, GameMode::Other)]
+    #[test_case(18, GameMode::Highlander)]
+    fn test_game_mode_from_player_count(count: usize, expected: GameMode) {
+        assert_eq!(expected, game_mode_from_player_count(count));
+    }
+
+    #[test]
+    fn test_substitutes() {
+        let mut log = parse("3579548.json");
+        add_substitute(&mut log, TeamId::Red, 76561198000000001, 600);
+        add_substitute(&mut log, TeamId::Blue, 76561198000000002, 300);
+
+        assert_eq!(14, log.players.len());
+        let detected = detect_game_mode(&log);
+        assert_eq!(GameMode::Sixes, detected.mode);
+        assert!(detected.confidence > 0.9);
+    }
+
+    #[test]
+    fn test_missing_player_lowers_confidence() {
+        let full = detect_game_mode(&parse("3579548.json"));
+        let missing = detect_game_mode(&parse("3578739.json"));
+
+        assert_eq!(GameMode::Sixes, missing.mode);
+        assert!(missing.confidence < full.confidence);
+    }
+
+    #[test]
+    fn test_prolander() {
+        let mut log = parse("550237.json");
+        keep_classes(&mut log, &PROLANDER_CLASSES);
+
+        assert_eq!(14, log.players.len());
+        assert_eq!(GameMode::Prolander, detect_game_mode(&log).mode);
+
+        for player in log.players.values_mut() {
+            for class in player.class_stats.iter_mut() {
+                if class.class == Class::Sniper {
+                    class.class = Class::Scout;
+                }
+            }
+        }
+        assert_eq!(GameMode::Sevens, detect_game_mode(&log).mode);
+    }
+
+    #[test]
+    fn test_bball() {
+        let mut log = parse("550237.json");
+        keep_classes(&mut log, &[Class::Soldier, Class::Medic]);
+
+        let on_cp = detect_game_mode(&log);
+        log.info.map = "bball_tf_v2".into();
+        let on_bball = detect_game_mode(&log);
+
+        assert_eq!(GameMode::Twos, on_bball.mode);
+        assert!(on_cp.confidence < on_bball.confidence);
+    }
+}
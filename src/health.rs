@@ -0,0 +1,171 @@
+use crate::metrics::Metrics;
+use chrono::{DateTime, Utc};
+use sd_notify::NotifyState;
+use serde::Serialize;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use std::sync::{Arc, Mutex};
+use tokio::time::{interval, timeout, Duration, Instant};
+use tracing::{info, warn};
+
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tracks whether the normalizer is making progress, for the health endpoint and the systemd watchdog
+pub struct Health {
+    /// Connections used only for the checks, so they still work when the normalizer holds all of its own
+    pool: PgPool,
+    raw_pool: PgPool,
+    /// Time a single step of a pass can take before the normalizer is considered stuck
+    stall_timeout: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    last_pass: Option<DateTime<Utc>>,
+    last_progress: Instant,
+    idle: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub alive: bool,
+    pub database: Option<bool>,
+    pub raw_database: Option<bool>,
+    pub last_pass: Option<DateTime<Utc>>,
+    /// Raw logs that haven't been normalized yet
+    pub backlog: i64,
+    pub upgrades_pending: i64,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.alive && self.database != Some(false) && self.raw_database != Some(false)
+    }
+}
+
+impl Health {
+    pub fn new(
+        database_url: &str,
+        raw_database_url: &str,
+        stall_timeout: Duration,
+    ) -> Result<Self, sqlx::Error> {
+        Ok(Health {
+            pool: check_pool(database_url)?,
+            raw_pool: check_pool(raw_database_url)?,
+            stall_timeout,
+            state: Mutex::new(State {
+                last_pass: None,
+                last_progress: Instant::now(),
+                idle: true,
+            }),
+        })
+    }
+
+    pub fn pass_started(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.idle = false;
+        state.last_progress = Instant::now();
+    }
+
+    /// Record that the pass is still moving, called for every log, rated log and refreshed view
+    pub fn progress(&self) {
+        self.state.lock().unwrap().last_progress = Instant::now();
+    }
+
+    pub fn pass_finished(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.idle = true;
+        state.last_pass = Some(Utc::now());
+    }
+
+    fn is_alive_at(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        state.idle || now.duration_since(state.last_progress) < self.stall_timeout
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.is_alive_at(Instant::now())
+    }
+
+    /// Report the liveness of the normalizer, including the database connections if `check_databases` is set
+    pub async fn report(&self, check_databases: bool) -> HealthReport {
+        let (database, raw_database) = if check_databases {
+            let (database, raw_database) =
+                tokio::join!(check_database(&self.pool), check_database(&self.raw_pool));
+            (Some(database), Some(raw_database))
+        } else {
+            (None, None)
+        };
+        let metrics = Metrics::global();
+        HealthReport {
+            alive: self.is_alive(),
+            database,
+            raw_database,
+            last_pass: self.state.lock().unwrap().last_pass,
+            backlog: metrics.raw_log_lag.get(),
+            upgrades_pending: metrics.upgrades_pending.get(),
+        }
+    }
+}
+
+fn check_pool(url: &str) -> Result<PgPool, sqlx::Error> {
+    PgPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(DATABASE_TIMEOUT)
+        .connect_lazy(url)
+}
+
+async fn check_database(pool: &PgPool) -> bool {
+    matches!(
+        timeout(DATABASE_TIMEOUT, sqlx::query("SELECT 1").execute(pool)).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Tell systemd the normalizer has started and keep pinging its watchdog for as long as the normalizer is alive
+pub async fn watchdog(health: Arc<Health>) {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!(error = display(e), "failed to notify systemd");
+    }
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let period = Duration::from_micros(usec) / 2;
+    info!(period = debug(period), "pinging systemd watchdog");
+    let mut ticks = interval(period);
+    loop {
+        ticks.tick().await;
+        if health.is_alive() {
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                warn!(error = display(e), "failed to ping systemd watchdog");
+            }
+        } else {
+            warn!("normalizer is stuck, not pinging systemd watchdog");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "postgres://localhost/logs";
+
+    #[tokio::test]
+    async fn test_is_alive() {
+        let health = Health::new(URL, URL, Duration::from_secs(60)).unwrap();
+        let now = Instant::now();
+        assert!(health.is_alive_at(now + Duration::from_secs(120)));
+
+        health.pass_started();
+        assert!(health.is_alive_at(now + Duration::from_secs(30)));
+        assert!(!health.is_alive_at(now + Duration::from_secs(120)));
+
+        health.progress();
+        health.pass_finished();
+        assert!(health.is_alive_at(now + Duration::from_secs(120)));
+        assert!(health.report(false).await.last_pass.is_some());
+    }
+}
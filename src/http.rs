@@ -0,0 +1,125 @@
+use crate::health::Health;
+use crate::metrics::Metrics;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+/// Largest request that is read, only the request line is used
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Serve the metrics on `/metrics`, liveness on `/health` and readiness including the databases on `/ready`
+pub async fn serve(listener: TcpListener, health: Arc<Health>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let health = health.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, &health).await {
+                        debug!(error = display(e), "failed to handle http request");
+                    }
+                });
+            }
+            Err(e) => {
+                warn!(error = display(e), "failed to accept http connection");
+            }
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, health: &Health) -> io::Result<()> {
+    let mut buffer = vec![0; MAX_REQUEST_SIZE];
+    let mut read = 0;
+    while read < buffer.len() {
+        let bytes = stream.read(&mut buffer[read..]).await?;
+        read += bytes;
+        if bytes == 0 || buffer[..read].windows(4).any(|end| end == b"\r\n\r\n") {
+            break;
+        }
+    }
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let mut request_line = request.split_whitespace();
+    let response = route(request_line.next(), request_line.next(), health).await;
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn route(method: Option<&str>, path: Option<&str>, health: &Health) -> String {
+    match (method, path) {
+        (Some("GET"), Some("/metrics")) => response(
+            "200 OK",
+            "text/plain; version=0.0.4",
+            &Metrics::global().render(),
+        ),
+        (Some("GET"), Some(path @ ("/health" | "/ready"))) => {
+            let report = health.report(path == "/ready").await;
+            let status = if report.is_healthy() {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let body = serde_json::to_string(&report).unwrap_or_default();
+            response(status, "application/json", &body)
+        }
+        (Some("GET"), Some(_)) => response("404 Not Found", "text/plain", "not found\n"),
+        _ => response(
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n",
+        ),
+    }
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    async fn get(address: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // nothing listens on the discard port, so the database checks fail
+        let url = "postgres://127.0.0.1:9/logs";
+        let health = Health::new(url, url, Duration::from_secs(60)).unwrap();
+        tokio::spawn(serve(listener, Arc::new(health)));
+
+        Metrics::global().logs_upgraded.inc();
+        let response = get(address, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("normalizer_logs_upgraded_total"));
+
+        let response = get(address, "/health").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\"alive\":true"));
+
+        let response = get(address, "/ready").await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("\"database\":false"));
+
+        let response = get(address, "/other").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -1,80 +1,498 @@
+mod checkpoint;
+mod config;
 mod data;
 mod database;
+mod duplicates;
+mod failures;
+mod game_mode;
+mod health;
+mod http;
+mod maps;
+mod matches;
+mod merge;
+mod metrics;
 mod normalized;
+mod notify;
+mod passes;
+mod rating;
 pub mod raw;
+mod rollup;
+mod shutdown;
+mod teams;
+mod validity;
+mod views;
+mod weapons;
 
-use crate::database::{store_log, upgrade};
+use crate::checkpoint::{
+    clear_checkpoint, load_checkpoint, store_checkpoint, Checkpoint, PassPhase,
+};
+use crate::config::Config;
+use crate::database::{revalidate, store_log, store_validity_thresholds, upgrade};
+use crate::failures::{
+    clear_failure, is_connection_error, store_failure, take_retries, RetryPolicy,
+};
+use crate::health::{watchdog, Health};
+use crate::maps::MapCatalog;
+use crate::merge::{find_split_source, merge_logs};
+use crate::metrics::Metrics;
 use crate::normalized::NormalizedLog;
-use anyhow::{Context, Error};
+use crate::notify::LogNotifier;
+use crate::rating::update_ratings;
+use crate::rollup::{check_rollup, rebuild_rollup};
+use crate::shutdown::Shutdown;
+use crate::views::ViewRefresher;
+use crate::weapons::{reset_unknown_reports, sync_weapons, WeaponRegistry};
+use anyhow::{anyhow, Context, Error};
 use main_error::MainError;
 use sqlx::pool::PoolOptions;
 use sqlx::PgPool;
-use tokio::time::{sleep, Duration};
-use tracing::{error, info, instrument};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::time::Duration;
+use tracing::{error, info, instrument, warn};
 
-const OLD_VERSION: i16 = 1;
-const VERSION: i16 = 2;
+const VERSION: i16 = 21;
 
 #[tokio::main]
 async fn main() -> Result<(), MainError> {
     tracing_subscriber::fmt::init();
     let database_url = dotenvy::var("DATABASE_URL")?;
     let raw_database_url = dotenvy::var("RAW_DATABASE_URL")?;
+    let config = Config::from_env()?;
+    if let Some(path) = &config.map_catalog {
+        MapCatalog::init(MapCatalog::load(path)?);
+    }
+    if let Some(path) = &config.weapon_registry {
+        WeaponRegistry::init(WeaponRegistry::load(path)?);
+    }
+    Config::init(config);
+    let config = Config::global();
+
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("check-rollups") {
+        let repair = args.next().as_deref() == Some("--repair");
+        return Ok(check_rollups(&database_url, repair).await?);
+    }
+
+    let mut shutdown = Shutdown::listen().context("Failed to listen for shutdown signals")?;
+    let health = Arc::new(Health::new(
+        &database_url,
+        &raw_database_url,
+        Duration::from_secs(config.stall_timeout),
+    )?);
+    if let Some(address) = config.http_address {
+        let listener = TcpListener::bind(address)
+            .await
+            .with_context(|| format!("Failed to listen on {}", address))?;
+        info!(
+            address = display(address),
+            "serving metrics and health checks"
+        );
+        tokio::spawn(http::serve(listener, health.clone()));
+    }
+    tokio::spawn(watchdog(health.clone()));
+
+    let mut views = ViewRefresher::new(
+        config.refresh_views,
+        Duration::from_secs(config.view_refresh_interval),
+    );
+    let mut notifier = LogNotifier::new(&raw_database_url, &config.raw_log_channel);
 
     loop {
-        normalize(&database_url, &raw_database_url).await?;
-        sleep(Duration::from_secs(15 * 60)).await;
+        let timer = Metrics::global().pass_duration.start_timer();
+        health.pass_started();
+        normalize(
+            &database_url,
+            &raw_database_url,
+            config,
+            &mut views,
+            &health,
+            &shutdown,
+        )
+        .await?;
+        if shutdown.is_requested() {
+            break;
+        }
+        health.pass_finished();
+        timer.observe_duration();
+        // new logs are normally announced by the fetcher, the timer catches any that weren't
+        tokio::select! {
+            _ = notifier.wait(Duration::from_secs(config.poll_interval)) => {}
+            _ = shutdown.wait() => break,
+        }
     }
+    info!("normalizer stopped");
+    Ok(())
 }
 
-async fn normalize(database_url: &str, raw_database_url: &str) -> Result<(), Error> {
+async fn normalize(
+    database_url: &str,
+    raw_database_url: &str,
+    config: &Config,
+    views: &mut ViewRefresher,
+    health: &Health,
+    shutdown: &Shutdown,
+) -> Result<(), Error> {
     let pool = PoolOptions::new()
-        .max_connections(2)
+        .max_connections(config.pool_size)
         .connect(database_url)
         .await
         .context("Failed to connect to log database")?;
     let raw_pool = PoolOptions::new()
-        .max_connections(2)
+        .max_connections(config.raw_pool_size)
         .connect(raw_database_url)
         .await
         .context("Failed to connect to raw log database")?;
 
+    sync_weapons(&pool, WeaponRegistry::global())
+        .await
+        .context("Failed to update weapons")?;
+    reset_unknown_reports();
+    if store_validity_thresholds(&pool, &config.validity)
+        .await
+        .context("Failed to update validity thresholds")?
+    {
+        warn!("validity thresholds changed, revalidating stored logs");
+    }
+
+    let metrics = Metrics::global();
+    let retry = RetryPolicy::new(
+        config.retry_attempts,
+        Duration::from_secs(config.retry_delay),
+    );
+    let retries = take_retries(&pool)
+        .await
+        .context("Failed to get logs to retry")?;
     let max = get_max_log(&raw_pool)
         .await
         .context("Failed to get max raw log")?;
-    let old = get_min_old_stored_log(&pool, VERSION)
+    let target = config.target_version;
+    let old = get_min_old_stored_log(&pool, target)
         .await
         .context("Failed to get min processed old log")?;
     let from = get_max_stored_log(&pool)
         .await
         .context("Failed to get min processed log")?;
+    metrics.raw_log_lag.set((max - from).max(0) as i64);
+    metrics.upgrades_pending.set(
+        count_old_stored_logs(&pool, target)
+            .await
+            .context("Failed to count old logs")?,
+    );
+    let checkpoint = load_checkpoint(&pool)
+        .await
+        .context("Failed to load checkpoint")?;
+    if let Some(checkpoint) = checkpoint {
+        info!(
+            phase = debug(checkpoint.phase),
+            next_id = checkpoint.next_id,
+            "resuming interrupted pass"
+        );
+        // the views weren't refreshed for the logs stored before the shutdown
+        views.invalidate();
+    }
 
     if let Some(old) = old {
-        for id in old..=from {
-            info!(id = id, from = OLD_VERSION, to = VERSION, "migrating");
-            if let Some(log) = get_log(&raw_pool, id).await? {
-                upgrade(&pool, id, &log, OLD_VERSION, VERSION).await?;
-            } else {
-                error!(id = id, "invalid");
+        for id in Checkpoint::upgrade_start(checkpoint, old, target)..=from {
+            if shutdown.is_requested() {
+                return stop(&pool, PassPhase::Upgrade, id, target).await;
+            }
+            health.progress();
+            let Some(version) = get_stored_version(&pool, id).await? else {
+                continue;
+            };
+            if version >= target {
+                continue;
+            }
+            info!(id = id, from = version, to = target, "migrating");
+            let result = retry
+                .run(id, shutdown, || {
+                    upgrade_log(&pool, &raw_pool, id, version, target)
+                })
+                .await;
+            if let Err(e) = result {
+                if shutdown.is_requested() {
+                    return stop(&pool, PassPhase::Upgrade, id, target).await;
+                }
+                fail(&pool, id, PassPhase::Upgrade, &retry, e).await?;
             }
+            views.invalidate();
+            metrics.upgrades_pending.dec();
         }
     }
 
+    for id in get_revalidate_logs(&pool)
+        .await
+        .context("Failed to get logs to revalidate")?
+    {
+        if shutdown.is_requested() {
+            return stop(&pool, PassPhase::Normalize, from + 1, target).await;
+        }
+        health.progress();
+        let result = retry
+            .run(id, shutdown, || revalidate_log(&pool, &raw_pool, id))
+            .await;
+        if let Err(e) = result {
+            if shutdown.is_requested() {
+                return stop(&pool, PassPhase::Normalize, from + 1, target).await;
+            }
+            fail(&pool, id, PassPhase::Upgrade, &retry, e).await?;
+        }
+        views.invalidate();
+    }
+
+    for id in retries {
+        if shutdown.is_requested() {
+            return stop(&pool, PassPhase::Normalize, from + 1, target).await;
+        }
+        health.progress();
+        info!(id = id, "retrying failed log");
+        let result = retry
+            .run(id, shutdown, || normalize_log(&pool, &raw_pool, id))
+            .await;
+        match result {
+            Ok(()) => clear_failure(&pool, id)
+                .await
+                .context("Failed to clear failed log")?,
+            Err(_) if shutdown.is_requested() => {
+                return stop(&pool, PassPhase::Normalize, from + 1, target).await;
+            }
+            Err(e) => fail(&pool, id, PassPhase::Normalize, &retry, e).await?,
+        }
+        views.invalidate();
+    }
+
     for id in (from + 1)..=max {
-        if let Some(log) = get_log(&raw_pool, id).await? {
-            info!(id = id, map = display(&log.info.map), "normalizing");
-            store_log(&pool, id, &log).await?;
-        } else {
-            error!(id = id, "invalid");
+        if shutdown.is_requested() {
+            return stop(&pool, PassPhase::Normalize, id, target).await;
         }
+        health.progress();
+        let result = retry
+            .run(id, shutdown, || normalize_log(&pool, &raw_pool, id))
+            .await;
+        if let Err(e) = result {
+            if shutdown.is_requested() {
+                return stop(&pool, PassPhase::Normalize, id, target).await;
+            }
+            fail(&pool, id, PassPhase::Normalize, &retry, e).await?;
+        }
+        views.invalidate();
+        metrics.raw_log_lag.set((max - id) as i64);
+    }
+
+    health.progress();
+    update_ratings(&pool, health)
+        .await
+        .context("Failed to update ratings")?;
+
+    health.progress();
+    views
+        .refresh(&pool, health)
+        .await
+        .context("Failed to refresh materialized views")?;
+
+    if checkpoint.is_some() {
+        clear_checkpoint(&pool)
+            .await
+            .context("Failed to clear checkpoint")?;
     }
 
     Ok(())
 }
 
+/// Normalize a new log and store it
+async fn normalize_log(pool: &PgPool, raw_pool: &PgPool, id: i32) -> Result<(), Error> {
+    let metrics = Metrics::global();
+    if let Some(log) = get_log(raw_pool, id).await? {
+        info!(id = id, map = display(&log.info.map), "normalizing");
+        let (log, merged_from) = merge_split_log(pool, raw_pool, id, log).await?;
+        let timer = metrics.store_log_duration.start_timer();
+        let invalid_reasons = store_log(pool, id, &log, &merged_from)
+            .await
+            .context("Failed to store log")?;
+        timer.observe_duration();
+        metrics.logs_normalized.inc();
+        metrics.reject(invalid_reasons);
+    } else {
+        error!(id = id, "invalid");
+        metrics
+            .logs_rejected
+            .with_label_values(&["invalid_raw_log"])
+            .inc();
+    }
+    Ok(())
+}
+
+/// Upgrade a stored log from `version` to `target`
+async fn upgrade_log(
+    pool: &PgPool,
+    raw_pool: &PgPool,
+    id: i32,
+    version: i16,
+    target: i16,
+) -> Result<(), Error> {
+    if let Some(log) = get_stored_log(pool, raw_pool, id).await? {
+        upgrade(pool, id, &log, version, target)
+            .await
+            .context("Failed to upgrade log")?;
+        Metrics::global().logs_upgraded.inc();
+    } else {
+        error!(id = id, "invalid");
+    }
+    Ok(())
+}
+
+/// Validate a stored log again after the validity thresholds changed
+async fn revalidate_log(pool: &PgPool, raw_pool: &PgPool, id: i32) -> Result<(), Error> {
+    if let Some(log) = get_stored_log(pool, raw_pool, id).await? {
+        let invalid_reasons = revalidate(pool, id, &log)
+            .await
+            .context("Failed to revalidate log")?;
+        info!(
+            id = id,
+            invalid_reasons = invalid_reasons.bits(),
+            "revalidated"
+        );
+    } else {
+        error!(id = id, "invalid");
+    }
+    Ok(())
+}
+
+/// Move a log to the failed logs so the pass can continue, unless the error means the database is unreachable
+async fn fail(
+    pool: &PgPool,
+    id: i32,
+    phase: PassPhase,
+    retry: &RetryPolicy,
+    e: Error,
+) -> Result<(), Error> {
+    if is_connection_error(&e) {
+        return Err(e.context(format!("Failed to process log {}", id)));
+    }
+    error!(
+        id,
+        phase = debug(phase),
+        error = display(format_args!("{:#}", e)),
+        "giving up on log"
+    );
+    store_failure(pool, id, phase, retry.attempts(), &e)
+        .await
+        .context("Failed to store failed log")?;
+    Metrics::global().logs_failed.inc();
+    Ok(())
+}
+
+/// Record where the pass stopped so the next pass can resume from there
+async fn stop(pool: &PgPool, phase: PassPhase, next_id: i32, target: i16) -> Result<(), Error> {
+    store_checkpoint(
+        pool,
+        Checkpoint {
+            phase,
+            next_id,
+            target_version: target,
+        },
+    )
+    .await
+    .context("Failed to store checkpoint")?;
+    info!(phase = debug(phase), next_id, "stopped pass");
+    Ok(())
+}
+
+/// Compare the rollup tables to a full recomputation, optionally rebuilding them if they differ
+async fn check_rollups(database_url: &str, repair: bool) -> Result<(), Error> {
+    let pool = PoolOptions::new()
+        .max_connections(1)
+        .connect(database_url)
+        .await
+        .context("Failed to connect to log database")?;
+    let mismatches = check_rollup(&pool)
+        .await
+        .context("Failed to check player stats rollup")?;
+    if mismatches.is_empty() {
+        info!("player stats rollup is consistent");
+        return Ok(());
+    }
+    for mismatch in mismatches.iter().take(20) {
+        error!(
+            game_mode = debug(mismatch.game_mode),
+            map = display(&mismatch.clean_map),
+            year = mismatch.year,
+            month = mismatch.month,
+            class = debug(mismatch.class),
+            steam_id = mismatch.steam_id,
+            "player stats rollup differs"
+        );
+    }
+    if repair {
+        rebuild_rollup(&pool)
+            .await
+            .context("Failed to rebuild player stats rollup")?;
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} player stats rollup rows differ from the logs",
+            mismatches.len()
+        ))
+    }
+}
+
+/// Merge the log with the stored log it continues after a server crash or map restart
+async fn merge_split_log(
+    pool: &PgPool,
+    raw_pool: &PgPool,
+    id: i32,
+    log: NormalizedLog,
+) -> Result<(NormalizedLog, Vec<i32>), Error> {
+    let Some(previous) = find_split_source(pool, id, &log).await? else {
+        return Ok((log, Vec::new()));
+    };
+    let mut sources = previous.sources();
+    sources.push(id);
+    match get_merged_log(raw_pool, &sources).await? {
+        Some(merged) => {
+            info!(id = id, sources = debug(&sources), "merging split log");
+            Ok((merged, sources))
+        }
+        None => Ok((log, Vec::new())),
+    }
+}
+
+/// Get the log as it was stored, merging the parts again if it was merged from a split log
+async fn get_stored_log(
+    pool: &PgPool,
+    raw_pool: &PgPool,
+    id: i32,
+) -> Result<Option<NormalizedLog>, Error> {
+    let merged_from = sqlx::query!("SELECT merged_from FROM logs WHERE id = $1", id)
+        .fetch_one(pool)
+        .await?
+        .merged_from;
+    if merged_from.is_empty() {
+        get_log(raw_pool, id).await
+    } else {
+        get_merged_log(raw_pool, &merged_from).await
+    }
+}
+
+async fn get_merged_log(
+    raw_pool: &PgPool,
+    sources: &[i32],
+) -> Result<Option<NormalizedLog>, Error> {
+    let mut parts = Vec::with_capacity(sources.len());
+    for source in sources {
+        let Some(part) = get_log(raw_pool, *source).await? else {
+            return Ok(None);
+        };
+        parts.push(part);
+    }
+    let mut parts = parts.into_iter();
+    Ok(parts.next().map(|first| merge_logs(first, parts)))
+}
+
 async fn get_min_old_stored_log(pool: &PgPool, version: i16) -> Result<Option<i32>, Error> {
     Ok(sqlx::query!(
-        r#"SELECT MIN(id) as "id" from logs WHERE version < $1"#,
+        r#"SELECT MIN(id) as "id" from logs WHERE version < $1 AND id NOT IN (SELECT id FROM failed_logs)"#,
         version
     )
     .fetch_optional(pool)
@@ -82,12 +500,48 @@ async fn get_min_old_stored_log(pool: &PgPool, version: i16) -> Result<Option<i3
     .and_then(|row| row.id))
 }
 
+async fn count_old_stored_logs(pool: &PgPool, version: i16) -> Result<i64, Error> {
+    Ok(sqlx::query!(
+        r#"SELECT COUNT(*) as "count!" from logs WHERE version < $1 AND id NOT IN (SELECT id FROM failed_logs)"#,
+        version
+    )
+    .fetch_one(pool)
+    .await?
+    .count)
+}
+
+/// Get the stored logs that need to be validated with changed thresholds, failed logs are skipped
+async fn get_revalidate_logs(pool: &PgPool) -> Result<Vec<i32>, Error> {
+    Ok(sqlx::query!(
+        r#"SELECT id from logs WHERE revalidate AND id NOT IN (SELECT id FROM failed_logs) ORDER BY id"#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| row.id)
+    .collect())
+}
+
+/// Get the version of a stored log, failed logs are skipped until they are marked to be retried
+async fn get_stored_version(pool: &PgPool, id: i32) -> Result<Option<i16>, Error> {
+    Ok(sqlx::query!(
+        r#"SELECT version from logs WHERE id = $1 AND id NOT IN (SELECT id FROM failed_logs)"#,
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|row| row.version))
+}
+
+/// Get the last processed log, new logs that failed count as processed so they aren't attempted again
 async fn get_max_stored_log(pool: &PgPool) -> Result<i32, Error> {
-    Ok(sqlx::query!(r#"SELECT MAX(id) as id from logs"#)
-        .fetch_one(pool)
-        .await?
-        .id
-        .unwrap_or_default())
+    Ok(sqlx::query!(
+        r#"SELECT GREATEST((SELECT MAX(id) from logs), (SELECT MAX(id) FROM failed_logs WHERE phase = 'normalize')) as id"#
+    )
+    .fetch_one(pool)
+    .await?
+    .id
+    .unwrap_or_default())
 }
 
 async fn get_max_log(pool: &PgPool) -> Result<i32, Error> {
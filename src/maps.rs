@@ -0,0 +1,181 @@
+use crate::data::MapType;
+use anyhow::{Context, Error};
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::sync::OnceLock;
+
+const DEFAULT_CATALOG: &str = include_str!("../maps.toml");
+
+static CATALOG: OnceLock<MapCatalog> = OnceLock::new();
+
+/// Known maps with their canonical name and type, loaded from `maps.toml`
+#[derive(Debug, Clone, Deserialize)]
+pub struct MapCatalog {
+    #[serde(default, rename = "prefix")]
+    prefixes: Vec<PrefixEntry>,
+    #[serde(default, rename = "map")]
+    maps: Vec<MapEntry>,
+    #[serde(skip, default = "version_suffix")]
+    version_suffix: Regex,
+}
+
+/// Fallback type for maps that aren't listed in the catalog
+#[derive(Debug, Clone, Deserialize)]
+struct PrefixEntry {
+    prefix: String,
+    #[serde(rename = "type")]
+    map_type: MapType,
+    #[serde(default)]
+    stopwatch: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MapEntry {
+    name: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(rename = "type")]
+    map_type: MapType,
+    #[serde(default)]
+    stopwatch: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapInfo {
+    pub name: String,
+    pub map_type: MapType,
+    pub stopwatch: bool,
+}
+
+fn version_suffix() -> Regex {
+    Regex::new(r"((_(a|b|beta|u|r|v|rc|final|comptf|ugc|f)?[0-9]*[a-z]?)?(_(a|b|beta|u|r|v|rc|final|comptf|ugc|f)?[0-9]*[a-z]?(_nb[0-9]*)?)|([0-9]+[a-z]?))(\.[a-z0-9]+)?$").unwrap()
+}
+
+impl MapCatalog {
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        toml::from_str(content).context("Failed to parse map catalog")
+    }
+
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read map catalog {}", path))?;
+        Self::parse(&content)
+    }
+
+    /// Set the catalog used for normalizing logs, can only be done once
+    pub fn init(catalog: MapCatalog) {
+        if CATALOG.set(catalog).is_err() {
+            panic!("map catalog already initialized");
+        }
+    }
+
+    /// The catalog set by [`MapCatalog::init`], or the bundled catalog if none has been set
+    pub fn global() -> &'static MapCatalog {
+        CATALOG.get_or_init(|| Self::parse(DEFAULT_CATALOG).expect("invalid bundled map catalog"))
+    }
+
+    /// Strip the workshop prefix and any version suffix from the map name
+    fn strip_version<'a>(&self, map: &'a str) -> &'a str {
+        let map = map.strip_prefix("workshop/").unwrap_or(map);
+        match self.version_suffix.find(map) {
+            Some(suffix) => &map[0..suffix.start()],
+            None => map,
+        }
+    }
+
+    pub fn lookup(&self, map: &str) -> MapInfo {
+        let stripped = self.strip_version(map);
+        let workshop_stripped = map.strip_prefix("workshop/").unwrap_or(map);
+        let entry = self.maps.iter().find(|entry| {
+            [stripped, workshop_stripped]
+                .iter()
+                .any(|name| entry.name == *name || entry.aliases.iter().any(|alias| alias == name))
+        });
+
+        match entry {
+            Some(entry) => MapInfo {
+                name: entry.name.clone(),
+                map_type: entry.map_type,
+                stopwatch: entry.stopwatch,
+            },
+            None => {
+                let prefix = self
+                    .prefixes
+                    .iter()
+                    .find(|prefix| stripped.starts_with(&prefix.prefix));
+                MapInfo {
+                    name: stripped.to_string(),
+                    map_type: prefix.map(|prefix| prefix.map_type).unwrap_or_default(),
+                    stopwatch: prefix.map(|prefix| prefix.stopwatch).unwrap_or_default(),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("", "", MapType::Other, false)]
+    #[test_case("pl_upward", "pl_upward", MapType::Stopwatch, true)]
+    #[test_case("pl_barnblitz_pro4", "pl_barnblitz_pro", MapType::Stopwatch, true)]
+    #[test_case("pl_badwater_pro_v9", "pl_badwater_pro", MapType::Stopwatch, true)]
+    #[test_case("cp_snakewater_final1", "cp_snakewater", MapType::Cp, false)]
+    #[test_case("workshop/cp_process_final.ugc12345", "cp_process", MapType::Cp, false)]
+    #[test_case("cp_gravelpit", "cp_gravelpit", MapType::Stopwatch, true)]
+    #[test_case("cp_steel_f12", "cp_steel", MapType::Stopwatch, true)]
+    #[test_case("koth_product_rcx", "koth_product", MapType::Koth, false)]
+    #[test_case("koth_product_final", "koth_product", MapType::Koth, false)]
+    #[test_case("ctf_turbine_pro_rc4", "ctf_turbine_pro", MapType::Ctf, false)]
+    #[test_case("ultiduo_baloo_v2", "ultiduo_baloo", MapType::UltiDuo, false)]
+    #[test_case("bball_tf_v2", "bball_tf", MapType::BBall, false)]
+    #[test_case(
+        "plr_hightower_event",
+        "plr_hightower_event",
+        MapType::PayloadRace,
+        false
+    )]
+    #[test_case("pass_arena2_b14", "pass_arena2", MapType::Passtime, false)]
+    #[test_case("pd_watergate", "pd_watergate", MapType::PlayerDestruction, false)]
+    #[test_case("arena_lumberyard", "arena_lumberyard", MapType::Arena, false)]
+    #[test_case("cp_degrootkeep", "cp_degrootkeep", MapType::Medieval, true)]
+    #[test_case("sd_doomsday", "sd_doomsday", MapType::SpecialDelivery, false)]
+    #[test_case("tr_walkway_rc2", "tr_walkway", MapType::Training, false)]
+    #[test_case("mge_training_v8_beta4b", "mge_training", MapType::Training, false)]
+    fn test_lookup(map: &str, name: &str, map_type: MapType, stopwatch: bool) {
+        let catalog = MapCatalog::global();
+        assert_eq!(
+            MapInfo {
+                name: name.to_string(),
+                map_type,
+                stopwatch
+            },
+            catalog.lookup(map)
+        );
+    }
+
+    #[test_case("1.json", Some(""), MapType::Other, false)]
+    #[test_case("114840.json", Some(""), MapType::Other, false)]
+    #[test_case("134389.json", Some("pl_upward"), MapType::Stopwatch, true)]
+    #[test_case("550237.json", Some("pl_barnblitz_pro"), MapType::Stopwatch, true)]
+    #[test_case("2522305.json", Some("pl_badwater_pro"), MapType::Stopwatch, true)]
+    // unknown maps keep their name
+    #[test_case("3578739.json", None, MapType::Other, false)]
+    #[test_case("3579548.json", Some("cp_snakewater"), MapType::Cp, false)]
+    fn test_fixtures(file: &str, name: Option<&str>, map_type: MapType, stopwatch: bool) {
+        let content = std::fs::read_to_string(format!("tests/data/{}", file)).unwrap();
+        let log: serde_json::Value = serde_json::from_str(&content).unwrap();
+        let map = log["info"]["map"].as_str().unwrap();
+        assert_eq!(
+            MapInfo {
+                name: name.unwrap_or(map).to_string(),
+                map_type,
+                stopwatch
+            },
+            MapCatalog::global().lookup(map)
+        );
+    }
+}
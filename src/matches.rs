@@ -0,0 +1,345 @@
+use crate::config::{Config, MatchConfig};
+use crate::data::TeamId;
+use crate::normalized::NormalizedLog;
+use chrono::Duration;
+use sqlx::{Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, instrument};
+
+/// The parts of a log used to find other logs from the same match
+#[derive(Debug, Clone)]
+pub struct LogSummary {
+    pub id: i32,
+    pub date: i64,
+    pub uploader: i64,
+    pub title: String,
+    pub red: HashSet<i64>,
+    pub blue: HashSet<i64>,
+}
+
+impl LogSummary {
+    pub fn new(id: i32, log: &NormalizedLog) -> Self {
+        let roster = |team: TeamId| {
+            log.players
+                .iter()
+                .filter(|(_, player)| player.team == Some(team))
+                .map(|(steam_id, _)| u64::from(*steam_id) as i64)
+                .collect()
+        };
+        LogSummary {
+            id,
+            date: log.info.date as i64,
+            uploader: u64::from(log.info.uploader.id) as i64,
+            title: log.info.title.clone(),
+            red: roster(TeamId::Red),
+            blue: roster(TeamId::Blue),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Similarity {
+    pub score: f32,
+    /// Whether the red team of one log is the blue team in the other
+    pub swapped: bool,
+}
+
+/// Share of the smaller roster that is also in the other roster
+pub(crate) fn roster_overlap(a: &HashSet<i64>, b: &HashSet<i64>) -> f32 {
+    let smallest = a.len().min(b.len());
+    if smallest == 0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f32 / smallest as f32
+}
+
+fn title_words(title: &str) -> HashSet<String> {
+    title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn title_similarity(a: &str, b: &str) -> f32 {
+    let a = title_words(a);
+    let b = title_words(b);
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f32 / union as f32
+}
+
+/// Check how likely it is that two logs are from the same match
+pub fn similarity(a: &LogSummary, b: &LogSummary, config: &MatchConfig) -> Option<Similarity> {
+    let gap = (a.date - b.date).abs();
+    if gap > config.max_time_gap {
+        return None;
+    }
+
+    let same = roster_overlap(&a.red, &b.red).min(roster_overlap(&a.blue, &b.blue));
+    let swapped = roster_overlap(&a.red, &b.blue).min(roster_overlap(&a.blue, &b.red));
+    let (overlap, swapped) = if swapped > same {
+        (swapped, true)
+    } else {
+        (same, false)
+    };
+    if overlap < config.min_roster_overlap {
+        return None;
+    }
+
+    let time = 1.0 - gap as f32 / config.max_time_gap as f32;
+    let uploader = if a.uploader == b.uploader { 1.0 } else { 0.0 };
+    let score =
+        overlap * 0.6 + time * 0.2 + uploader * 0.1 + title_similarity(&a.title, &b.title) * 0.1;
+
+    (score >= config.min_score).then_some(Similarity { score, swapped })
+}
+
+/// Link the log to the match of the most similar recent log, creating the match if needed
+#[instrument(skip(tx, log))]
+pub async fn group_log(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    log: &NormalizedLog,
+) -> Result<Option<i32>, sqlx::Error> {
+    let existing = sqlx::query!("SELECT match_id FROM match_logs WHERE log_id = $1", id)
+        .fetch_optional(&mut **tx)
+        .await?;
+    if let Some(existing) = existing {
+        return Ok(Some(existing.match_id));
+    }
+
+    let config = &Config::global().matches;
+    let summary = LogSummary::new(id, log);
+    let candidates = get_candidates(tx, &summary, config).await?;
+    let best = candidates
+        .iter()
+        .filter_map(|candidate| Some((candidate.id, similarity(&summary, candidate, config)?)))
+        .max_by(|(_, a), (_, b)| a.score.total_cmp(&b.score));
+    let Some((candidate, similarity)) = best else {
+        return Ok(None);
+    };
+    debug!(
+        candidate,
+        score = similarity.score,
+        "found log from the same match"
+    );
+
+    let candidate_match = sqlx::query!(
+        r#"SELECT match_id, team_a as "team_a: TeamId" FROM match_logs WHERE log_id = $1"#,
+        candidate
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+    let (match_id, candidate_team_a) = match candidate_match {
+        Some(row) => (row.match_id, row.team_a),
+        None => {
+            let match_id = sqlx::query!("INSERT INTO matches DEFAULT VALUES RETURNING id")
+                .fetch_one(&mut **tx)
+                .await?
+                .id;
+            add_log(tx, match_id, candidate, TeamId::Red).await?;
+            (match_id, TeamId::Red)
+        }
+    };
+    let team_a = match (candidate_team_a, similarity.swapped) {
+        (TeamId::Red, false) | (TeamId::Blue, true) => TeamId::Red,
+        _ => TeamId::Blue,
+    };
+    add_log(tx, match_id, id, team_a).await?;
+    update_match_score(tx, match_id).await?;
+
+    Ok(Some(match_id))
+}
+
+async fn get_candidates(
+    tx: &mut Transaction<'_, Postgres>,
+    summary: &LogSummary,
+    config: &MatchConfig,
+) -> Result<Vec<LogSummary>, sqlx::Error> {
+    let date = chrono::DateTime::from_timestamp(summary.date, 0)
+        .unwrap_or_default()
+        .naive_utc();
+    let gap = Duration::seconds(config.max_time_gap);
+    let logs = sqlx::query!(
+        r#"SELECT id, extract(epoch from date)::BIGINT as "date!", uploader, title
+        FROM logs WHERE date BETWEEN $1 AND $2 AND id != $3 AND merged_into IS NULL AND duplicate_of IS NULL"#,
+        date - gap,
+        date + gap,
+        summary.id
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+    let ids: Vec<i32> = logs.iter().map(|log| log.id).collect();
+
+    let mut rosters: HashMap<i32, (HashSet<i64>, HashSet<i64>)> = HashMap::new();
+    let players = sqlx::query!(
+        r#"SELECT log_id, steam_id, team as "team: TeamId" FROM players WHERE log_id = ANY($1)"#,
+        &ids
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+    for player in players {
+        let roster = rosters.entry(player.log_id).or_default();
+        match player.team {
+            TeamId::Red => roster.0.insert(player.steam_id),
+            TeamId::Blue => roster.1.insert(player.steam_id),
+            TeamId::Other => false,
+        };
+    }
+
+    Ok(logs
+        .into_iter()
+        .map(|log| {
+            let (red, blue) = rosters.remove(&log.id).unwrap_or_default();
+            LogSummary {
+                id: log.id,
+                date: log.date,
+                uploader: log.uploader,
+                title: log.title,
+                red,
+                blue,
+            }
+        })
+        .collect())
+}
+
+/// Remove logs from their matches, updating the scores of the matches
+pub async fn remove_logs(
+    tx: &mut Transaction<'_, Postgres>,
+    ids: &[i32],
+) -> Result<(), sqlx::Error> {
+    let removed = sqlx::query!(
+        "DELETE FROM match_logs WHERE log_id = ANY($1) RETURNING match_id",
+        ids
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+    for match_id in removed.into_iter().map(|row| row.match_id) {
+        update_match_score(tx, match_id).await?;
+    }
+    Ok(())
+}
+
+async fn add_log(
+    tx: &mut Transaction<'_, Postgres>,
+    match_id: i32,
+    log_id: i32,
+    team_a: TeamId,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO match_logs(match_id, log_id, team_a) VALUES($1, $2, $3)",
+        match_id,
+        log_id,
+        team_a as TeamId
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Sum the scores of all logs in the match from the perspective of "team a"
+async fn update_match_score(
+    tx: &mut Transaction<'_, Postgres>,
+    match_id: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE matches SET
+            team_a_score = scores.team_a_score, team_b_score = scores.team_b_score,
+            team_a_wins = scores.team_a_wins, team_b_wins = scores.team_b_wins,
+            start_date = scores.start_date, end_date = scores.end_date
+        FROM (
+            SELECT
+                SUM(CASE WHEN team_a = 'red' THEN red_score ELSE blue_score END) AS team_a_score,
+                SUM(CASE WHEN team_a = 'red' THEN blue_score ELSE red_score END) AS team_b_score,
+                COUNT(*) FILTER (WHERE winner = team_a) AS team_a_wins,
+                COUNT(*) FILTER (WHERE winner != team_a AND winner != 'other') AS team_b_wins,
+                MIN(date) AS start_date,
+                MAX(date) AS end_date
+            FROM match_logs
+            INNER JOIN logs ON logs.id = match_logs.log_id
+            WHERE match_id = $1
+        ) AS scores
+        WHERE matches.id = $1"#,
+        match_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn summary(id: i32, file: &str) -> LogSummary {
+        let content = fs::read_to_string(format!("tests/data/{}", file)).unwrap();
+        let log: NormalizedLog = serde_json::from_str(&content).unwrap();
+        LogSummary::new(id, &log)
+    }
+
+    #[test]
+    fn test_same_match() {
+        let first = summary(1, "3579548.json");
+        let mut second = first.clone();
+        second.id = 2;
+        second.date += 45 * 60;
+        second.title = "Second map".into();
+
+        let similarity = similarity(&first, &second, &MatchConfig::default()).unwrap();
+        assert!(!similarity.swapped);
+    }
+
+    #[test]
+    fn test_swapped_teams() {
+        let first = summary(1, "550237.json");
+        let mut second = first.clone();
+        second.id = 2;
+        std::mem::swap(&mut second.red, &mut second.blue);
+
+        let similarity = similarity(&first, &second, &MatchConfig::default()).unwrap();
+        assert!(similarity.swapped);
+    }
+
+    #[test]
+    fn test_different_match() {
+        let first = summary(1, "3578739.json");
+        let second = summary(2, "3579548.json");
+
+        assert_eq!(None, similarity(&first, &second, &MatchConfig::default()));
+
+        let mut later = first.clone();
+        later.date += MatchConfig::default().max_time_gap + 1;
+        assert_eq!(None, similarity(&first, &later, &MatchConfig::default()));
+    }
+
+    #[test]
+    fn test_substitutes() {
+        let first = summary(1, "3579548.json");
+        let mut second = first.clone();
+        for (n, steam_id) in first.red.iter().take(2).enumerate() {
+            second.red.remove(steam_id);
+            second.red.insert(n as i64);
+        }
+
+        assert!(similarity(&first, &second, &MatchConfig::default()).is_some());
+
+        for (n, steam_id) in first.blue.iter().take(4).enumerate() {
+            second.blue.remove(steam_id);
+            second.blue.insert(100 + n as i64);
+        }
+        assert_eq!(None, similarity(&first, &second, &MatchConfig::default()));
+    }
+
+    #[test]
+    fn test_title_similarity() {
+        assert_eq!(
+            1.0,
+            title_similarity("Team A vs Team B", "team a vs. team b")
+        );
+        assert_eq!(0.0, title_similarity("serveme.tf #1", "Match 2"));
+    }
+}
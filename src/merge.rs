@@ -0,0 +1,405 @@
+use crate::config::{Config, MergeConfig};
+use crate::data::{RoundEnd, TeamId};
+use crate::matches::roster_overlap;
+use crate::normalized::{ClassNumbers, NormalizedLog, Player};
+use crate::passes::{shift_event_times, Correction, NormalizationPass, StopwatchScore};
+use crate::raw::{ClassStat, Team};
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::mem::take;
+use tracing::{debug, instrument};
+
+/// The parts of a stored log used to detect if a new log continues it
+#[derive(Debug, Clone)]
+pub struct LogPart {
+    pub id: i32,
+    pub map: String,
+    /// Upload time, which is the end of the log
+    pub date: i64,
+    pub length: u32,
+    pub last_round_end: RoundEnd,
+    pub red: HashSet<i64>,
+    pub blue: HashSet<i64>,
+    /// Raw logs the stored log was merged from, empty if it wasn't merged
+    pub merged_from: Vec<i32>,
+}
+
+impl LogPart {
+    pub fn new(id: i32, log: &NormalizedLog) -> Self {
+        let roster = |team: TeamId| {
+            log.players
+                .iter()
+                .filter(|(_, player)| player.team == Some(team))
+                .map(|(steam_id, _)| u64::from(*steam_id) as i64)
+                .collect()
+        };
+        LogPart {
+            id,
+            map: log.info.clean_map(),
+            date: log.info.date as i64,
+            length: log.info.total_length,
+            last_round_end: log.rounds.last().map(|round| round.end).unwrap_or_default(),
+            red: roster(TeamId::Red),
+            blue: roster(TeamId::Blue),
+            merged_from: Vec::new(),
+        }
+    }
+
+    /// The raw logs that make up this part
+    pub fn sources(&self) -> Vec<i32> {
+        if self.merged_from.is_empty() {
+            vec![self.id]
+        } else {
+            self.merged_from.clone()
+        }
+    }
+}
+
+/// Check if `next` picks up where `previous` stopped after a crash or map restart
+pub fn is_continuation(previous: &LogPart, next: &LogPart, config: &MergeConfig) -> bool {
+    if previous.map.is_empty() || previous.map != next.map {
+        return false;
+    }
+    let next_start = next.date - next.length as i64;
+    if (next_start - previous.date).abs() > config.max_gap {
+        return false;
+    }
+    if previous.length >= config.full_game_length && previous.last_round_end != RoundEnd::Truncated
+    {
+        return false;
+    }
+    roster_overlap(&previous.red, &next.red) >= config.min_roster_overlap
+        && roster_overlap(&previous.blue, &next.blue) >= config.min_roster_overlap
+}
+
+/// Find the stored log that the new log continues, if any
+#[instrument(skip(pool, log))]
+pub async fn find_split_source(
+    pool: &PgPool,
+    id: i32,
+    log: &NormalizedLog,
+) -> Result<Option<LogPart>, sqlx::Error> {
+    let config = &Config::global().merge;
+    let next = LogPart::new(id, log);
+    let start = chrono::DateTime::from_timestamp(next.date - next.length as i64, 0)
+        .unwrap_or_default()
+        .naive_utc();
+    let gap = chrono::Duration::seconds(config.max_gap);
+    let Some(previous) = sqlx::query!(
+        r#"SELECT id, extract(epoch from date)::BIGINT as "date!", length, merged_from,
+            (SELECT end_reason FROM rounds WHERE log_id = logs.id ORDER BY round DESC LIMIT 1) as "last_round_end: RoundEnd"
+        FROM logs
+        WHERE clean_map = $1 AND merged_into IS NULL AND duplicate_of IS NULL AND id < $2 AND date BETWEEN $3 AND $4
+        ORDER BY date DESC LIMIT 1"#,
+        next.map,
+        id,
+        start - gap,
+        start + gap,
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let mut part = LogPart {
+        id: previous.id,
+        map: next.map.clone(),
+        date: previous.date,
+        length: previous.length as u32,
+        last_round_end: previous.last_round_end.unwrap_or_default(),
+        red: HashSet::new(),
+        blue: HashSet::new(),
+        merged_from: previous.merged_from,
+    };
+    let players = sqlx::query!(
+        r#"SELECT steam_id, team as "team: TeamId" FROM players WHERE log_id = $1"#,
+        previous.id
+    )
+    .fetch_all(pool)
+    .await?;
+    for player in players {
+        match player.team {
+            TeamId::Red => part.red.insert(player.steam_id),
+            TeamId::Blue => part.blue.insert(player.steam_id),
+            TeamId::Other => false,
+        };
+    }
+
+    let found = is_continuation(&part, &next, config);
+    debug!(previous = part.id, found, "checked for split log");
+    Ok(found.then_some(part))
+}
+
+/// Combine the parts of a split log, in order, into a single log
+pub fn merge_logs(
+    mut merged: NormalizedLog,
+    rest: impl IntoIterator<Item = NormalizedLog>,
+) -> NormalizedLog {
+    let mut appended = false;
+    for part in rest {
+        append(&mut merged, part);
+        appended = true;
+    }
+    // stopwatch pairs can span the parts, so they have to be scored again
+    if appended {
+        let corrections = StopwatchScore.apply(&mut merged);
+        merged.corrections.extend(corrections);
+    }
+    merged
+}
+
+fn append(log: &mut NormalizedLog, mut next: NormalizedLog) {
+    let offset = log.info.total_length;
+    let first_round = log.rounds.len();
+
+    for round in next.rounds.iter_mut() {
+        shift_event_times(round, offset);
+    }
+    log.corrections.push(Correction {
+        pass: "merge_logs",
+        round: Some(first_round),
+        description: format!(
+            "appended {} rounds from split log, shifted event times by {}s",
+            next.rounds.len(),
+            offset
+        ),
+    });
+    log.corrections
+        .extend(next.corrections.into_iter().map(|correction| Correction {
+            round: correction.round.map(|round| round + first_round),
+            ..correction
+        }));
+    log.rounds.append(&mut next.rounds);
+
+    add_team(&mut log.teams.red, &next.teams.red);
+    add_team(&mut log.teams.blue, &next.teams.blue);
+
+    for (steam_id, player) in next.players {
+        match log.players.get_mut(&steam_id) {
+            Some(existing) => add_player(existing, player),
+            None => {
+                log.players.insert(steam_id, player);
+            }
+        }
+    }
+    for (steam_id, name) in next.names {
+        log.names.entry(steam_id).or_insert(name);
+    }
+    for (medic, targets) in next.heal_spread {
+        let spread = log.heal_spread.entry(medic).or_default();
+        for (target, heal) in targets {
+            *spread.entry(target).or_default() += heal;
+        }
+    }
+    add_class_numbers(&mut log.class_kills, next.class_kills);
+    add_class_numbers(&mut log.class_deaths, next.class_deaths);
+    add_class_numbers(&mut log.class_kill_assists, next.class_kill_assists);
+    log.chat.append(&mut next.chat);
+    log.kill_streaks
+        .extend(next.kill_streaks.into_iter().map(|mut streak| {
+            streak.time += offset as i32;
+            streak
+        }));
+
+    log.length += next.length;
+    let info = &mut log.info;
+    info.total_length += next.info.total_length;
+    info.date = next.info.date;
+    info.supplemental |= next.info.supplemental;
+    info.has_real_damage &= next.info.has_real_damage;
+    info.has_weapon_damage &= next.info.has_weapon_damage;
+    info.has_accuracy &= next.info.has_accuracy;
+    info.has_hp &= next.info.has_hp;
+    info.has_hp_real &= next.info.has_hp_real;
+    info.has_hs &= next.info.has_hs;
+    info.has_hs_hit &= next.info.has_hs_hit;
+    info.has_bs &= next.info.has_bs;
+    info.has_cp &= next.info.has_cp;
+    info.has_sb &= next.info.has_sb;
+    info.has_dt &= next.info.has_dt;
+    info.has_as &= next.info.has_as;
+    info.has_hr &= next.info.has_hr;
+    info.has_intel &= next.info.has_intel;
+}
+
+fn add_team(team: &mut Team, next: &Team) {
+    team.score += next.score;
+    team.kills += next.kills;
+    team.deaths += next.deaths;
+    team.dmg += next.dmg;
+    team.charges += next.charges;
+    team.drops += next.drops;
+    team.firstcaps += next.firstcaps;
+    team.caps += next.caps;
+}
+
+fn add_player(player: &mut Player, next: Player) {
+    player.team = player.team.or(next.team);
+    player.kills += next.kills;
+    player.deaths += next.deaths;
+    player.assists += next.assists;
+    player.suicides += next.suicides;
+    player.dmg += next.dmg;
+    player.dmg_real += next.dmg_real;
+    player.dt += next.dt;
+    player.dt_real += next.dt_real;
+    player.hr += next.hr;
+    player.lks = player.lks.max(next.lks);
+    player.ubers += next.ubers;
+    for (medigun, ubers) in next.ubertypes {
+        *player.ubertypes.entry(medigun).or_default() += ubers;
+    }
+    player.drops += next.drops;
+    player.medkits += next.medkits;
+    player.medkits_hp += next.medkits_hp;
+    player.backstabs += next.backstabs;
+    player.headshots += next.headshots;
+    player.headshots_hit += next.headshots_hit;
+    player.heal += next.heal;
+    player.cpc += next.cpc;
+    player.ic += next.ic;
+    // medic stats are averages and extremes that can't be combined, keep the first ones
+    player.medicstat = take(&mut player.medicstat).or(next.medicstat);
+
+    for class in next.class_stats {
+        match player
+            .class_stats
+            .iter_mut()
+            .find(|existing| existing.class == class.class)
+        {
+            Some(existing) => add_class_stat(existing, class),
+            None => player.class_stats.push(class),
+        }
+    }
+}
+
+fn add_class_stat(class: &mut ClassStat, next: ClassStat) {
+    class.kills += next.kills;
+    class.assists += next.assists;
+    class.deaths += next.deaths;
+    class.dmg += next.dmg;
+    class.total_time += next.total_time;
+    for (weapon, stats) in next.weapon {
+        match class.weapon.get_mut(&weapon) {
+            Some(existing) => existing.add(&stats),
+            None => {
+                class.weapon.insert(weapon, stats);
+            }
+        }
+    }
+}
+
+fn add_class_numbers<K: std::hash::Hash + Eq>(
+    numbers: &mut HashMap<K, ClassNumbers>,
+    next: HashMap<K, ClassNumbers>,
+) {
+    for (key, next) in next {
+        let existing = numbers.entry(key).or_default();
+        existing.scout += next.scout;
+        existing.soldier += next.soldier;
+        existing.pyro += next.pyro;
+        existing.demoman += next.demoman;
+        existing.heavyweapons += next.heavyweapons;
+        existing.engineer += next.engineer;
+        existing.medic += next.medic;
+        existing.sniper += next.sniper;
+        existing.spy += next.spy;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalized::Event;
+    use std::fs;
+
+    fn parse(file: &str) -> NormalizedLog {
+        let content = fs::read_to_string(format!("tests/data/{}", file)).unwrap();
+        serde_json::from_str(&content).unwrap()
+    }
+
+    /// Split a log after the given round, as if the server crashed
+    fn split(log: &NormalizedLog, rounds: usize) -> (NormalizedLog, NormalizedLog) {
+        let split_time = log.rounds[rounds - 1]
+            .events
+            .iter()
+            .map(Event::time)
+            .max()
+            .unwrap();
+        let mut first = log.clone();
+        first.rounds.truncate(rounds);
+        first.info.total_length = split_time;
+        first.info.date = log.info.date - (log.info.total_length - split_time) as u64;
+
+        let mut second = log.clone();
+        second.rounds.drain(0..rounds);
+        for round in second.rounds.iter_mut() {
+            for event in round.events.iter_mut() {
+                match event {
+                    Event::PointCap { time, .. }
+                    | Event::Charge { time, .. }
+                    | Event::Drop { time, .. }
+                    | Event::MedicDeath { time, .. }
+                    | Event::RoundWin { time, .. } => *time -= split_time,
+                    Event::Other => {}
+                }
+            }
+        }
+        second.info.total_length = log.info.total_length - split_time;
+        (first, second)
+    }
+
+    #[test]
+    fn test_detect_split() {
+        let log = parse("3579548.json");
+        let (first, second) = split(&log, 3);
+        let first = LogPart::new(1, &first);
+        let second = LogPart::new(2, &second);
+        let config = MergeConfig::default();
+
+        assert!(is_continuation(&first, &second, &config));
+
+        let mut later = second.clone();
+        later.date += config.max_gap + 60;
+        assert!(!is_continuation(&first, &later, &config));
+
+        let mut other_map = second.clone();
+        other_map.map = "cp_process".into();
+        assert!(!is_continuation(&first, &other_map, &config));
+
+        let other_teams = LogPart::new(2, &parse("3578739.json"));
+        assert!(!is_continuation(&first, &other_teams, &config));
+    }
+
+    #[test]
+    fn test_merge() {
+        let log = parse("3579548.json");
+        let (first, second) = split(&log, 3);
+        let player_kills = |log: &NormalizedLog| -> u32 {
+            log.players.values().map(|player| player.kills as u32).sum()
+        };
+        let expected_kills = player_kills(&first) + player_kills(&second);
+
+        let merged = merge_logs(first, [second]);
+
+        assert_eq!(log.rounds.len(), merged.rounds.len());
+        assert_eq!(log.info.total_length, merged.info.total_length);
+        assert_eq!(log.info.date, merged.info.date);
+        assert_eq!(expected_kills, player_kills(&merged));
+        assert_eq!(log.players.len(), merged.players.len());
+
+        let mut last_event_time = 0;
+        for event in merged.rounds.iter().flat_map(|round| round.events.iter()) {
+            assert!(event.time() >= last_event_time);
+            last_event_time = event.time();
+        }
+        let merge_corrections: Vec<Option<usize>> = merged
+            .corrections
+            .iter()
+            .filter(|correction| correction.pass == "merge_logs")
+            .map(|correction| correction.round)
+            .collect();
+        assert_eq!(vec![Some(3)], merge_corrections);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::validity::InvalidReasons;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Counters and timings of the normalizer, exposed in the Prometheus text format
+pub struct Metrics {
+    registry: Registry,
+    pub logs_normalized: IntCounter,
+    /// Logs that were skipped or stored as invalid, by reason
+    pub logs_rejected: IntCounterVec,
+    pub logs_upgraded: IntCounter,
+    /// Logs moved to the failed logs after all retries
+    pub logs_failed: IntCounter,
+    /// Stored logs that still need to be upgraded to the target version
+    pub upgrades_pending: IntGauge,
+    pub pass_duration: Histogram,
+    pub store_log_duration: Histogram,
+    /// Difference between the highest raw log id and the highest normalized log id
+    pub raw_log_lag: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("normalizer".into()), None).unwrap();
+        let logs_normalized =
+            IntCounter::new("logs_normalized_total", "Number of new logs stored").unwrap();
+        let logs_rejected = IntCounterVec::new(
+            Opts::new(
+                "logs_rejected_total",
+                "Number of logs skipped or stored as invalid",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let logs_upgraded =
+            IntCounter::new("logs_upgraded_total", "Number of stored logs upgraded").unwrap();
+        let logs_failed = IntCounter::new(
+            "logs_failed_total",
+            "Number of logs that failed all attempts",
+        )
+        .unwrap();
+        let upgrades_pending = IntGauge::new(
+            "upgrades_pending",
+            "Number of stored logs below the target version",
+        )
+        .unwrap();
+        let pass_duration = Histogram::with_opts(
+            HistogramOpts::new("pass_duration_seconds", "Duration of a normalization pass")
+                .buckets(exponential_buckets(0.1, 4.0, 10).unwrap()),
+        )
+        .unwrap();
+        let store_log_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "store_log_duration_seconds",
+                "Duration of storing a new log",
+            )
+            .buckets(exponential_buckets(0.005, 2.0, 12).unwrap()),
+        )
+        .unwrap();
+        let raw_log_lag = IntGauge::new(
+            "raw_log_lag",
+            "Number of raw log ids above the highest normalized log",
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(logs_normalized.clone()))
+            .unwrap();
+        registry.register(Box::new(logs_rejected.clone())).unwrap();
+        registry.register(Box::new(logs_upgraded.clone())).unwrap();
+        registry.register(Box::new(logs_failed.clone())).unwrap();
+        registry
+            .register(Box::new(upgrades_pending.clone()))
+            .unwrap();
+        registry.register(Box::new(pass_duration.clone())).unwrap();
+        registry
+            .register(Box::new(store_log_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(raw_log_lag.clone())).unwrap();
+
+        Metrics {
+            registry,
+            logs_normalized,
+            logs_rejected,
+            logs_upgraded,
+            logs_failed,
+            upgrades_pending,
+            pass_duration,
+            store_log_duration,
+            raw_log_lag,
+        }
+    }
+
+    /// The metrics shared by the whole normalizer
+    pub fn global() -> &'static Metrics {
+        METRICS.get_or_init(Metrics::new)
+    }
+
+    /// Count a stored log once for every rule it failed
+    pub fn reject(&self, reasons: InvalidReasons) {
+        for reason in reasons.log_reason_names() {
+            self.logs_rejected.with_label_values(&[reason]).inc();
+        }
+    }
+
+    /// Encode all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.logs_normalized.inc();
+        metrics.reject(InvalidReasons::TOO_SHORT | InvalidReasons::NO_MAP);
+        metrics.reject(InvalidReasons::NO_MAP);
+        metrics.store_log_duration.observe(0.02);
+        metrics.raw_log_lag.set(12);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("normalizer_logs_normalized_total 1\n"));
+        assert!(rendered.contains("normalizer_logs_rejected_total{reason=\"too_short\"} 1\n"));
+        assert!(rendered.contains("normalizer_logs_rejected_total{reason=\"no_map\"} 2\n"));
+        assert!(rendered.contains("normalizer_store_log_duration_seconds_count 1\n"));
+        assert!(rendered.contains("normalizer_raw_log_lag 12\n"));
+    }
+}
@@ -1,8 +1,11 @@
 pub use crate::data::TeamId;
-use crate::data::{GameMode, MapType};
+use crate::data::{MapType, RoundEnd};
+use crate::game_mode::{detect_game_mode, GameModeDetection};
+use crate::maps::MapCatalog;
+use crate::passes::{apply_passes, classify_round_ends, Correction};
 use crate::raw::RawLog;
 pub use crate::raw::{
-    ChatMessage, ClassNumbers, Event, KillStreak, Player, RoundPlayer, Team, Teams, Uploader,
+    ChatMessage, ClassNumbers, Event, KillStreak, Player, RoundPlayer, Teams, Uploader,
 };
 use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::Deserialize;
@@ -13,7 +16,6 @@ use steamid_ng::SteamID;
 #[derive(Debug, Clone, Deserialize)]
 #[serde(from = "crate::raw::RawLog")]
 pub struct NormalizedLog {
-    pub version: u8,
     pub length: u32,
     pub teams: Teams,
     pub players: HashMap<SteamID, Player>,
@@ -26,20 +28,60 @@ pub struct NormalizedLog {
     pub chat: Vec<ChatMessage>,
     pub info: Info,
     pub kill_streaks: Vec<KillStreak>,
+    pub stopwatch_pairs: Vec<StopwatchPair>,
+    pub corrections: Vec<Correction>,
 }
 
 impl NormalizedLog {
-    pub fn game_mode(&self) -> GameMode {
-        if self.info.map_type() == MapType::UltiDuo {
-            return GameMode::UltiDuo;
+    pub fn detect_game_mode(&self) -> GameModeDetection {
+        detect_game_mode(self)
+    }
+
+    /// Time played by the median player, the reported log length isn't reliable for older logs
+    pub fn median_play_time(&self) -> u32 {
+        let mut times: Vec<u32> = self
+            .players
+            .values()
+            .filter(|player| player.team.is_some())
+            .map(Player::play_time)
+            .collect();
+        times.sort_unstable();
+        times.get(times.len() / 2).copied().unwrap_or_default()
+    }
+
+    /// Total healing received by each player
+    pub fn heals_received(&self) -> HashMap<SteamID, u32> {
+        let mut heals_received: HashMap<SteamID, u32> = HashMap::new();
+        for heal_map in self.heal_spread.values() {
+            for (steam_id, heals) in heal_map {
+                *heals_received.entry(*steam_id).or_default() += heals;
+            }
         }
+        heals_received
+    }
+
+    /// Whether the player was in the game from the start of the match
+    pub fn is_starter(&self, steam_id: &SteamID) -> bool {
+        let Some(first_round) = self.rounds.first() else {
+            return true;
+        };
+        // players without kills or damage aren't listed in the round,
+        // but if they played long enough they have to have been there for the first round
+        let play_time = self
+            .players
+            .get(steam_id)
+            .map(Player::play_time)
+            .unwrap_or_default();
+        first_round.players.contains_key(steam_id)
+            || play_time + first_round.length >= self.info.total_length
+    }
 
-        match self.players.len() {
-            7..=9 => GameMode::Fours,
-            11..=13 => GameMode::Sixes,
-            14 => GameMode::Sevens,
-            17..=19 => GameMode::Highlander,
-            _ => GameMode::Other,
+    /// Team with the higher score, `Other` for a draw
+    pub fn winner(&self) -> TeamId {
+        match self.teams.red.score.cmp(&self.teams.blue.score) {
+            Ordering::Greater => TeamId::Red,
+            Ordering::Less => TeamId::Blue,
+            Ordering::Equal => TeamId::Other,
         }
     }
 }
@@ -71,21 +113,12 @@ pub struct Info {
 
 impl Info {
     pub fn map_type(&self) -> MapType {
-        if map_is_stopwatch(&self.map) {
-            MapType::Stopwatch
-        } else if self.map.starts_with("cp") {
-            MapType::Cp
-        } else if self.map.starts_with("koth") {
-            MapType::Koth
-        } else if self.map.starts_with("ctf") {
-            MapType::Ctf
-        } else if self.map.starts_with("ultiduo") {
-            MapType::UltiDuo
-        } else if self.map.starts_with("bball") {
-            MapType::BBall
-        } else {
-            MapType::Other
-        }
+        MapCatalog::global().lookup(&self.map).map_type
+    }
+
+    /// Map name without workshop prefix or version suffix
+    pub fn clean_map(&self) -> String {
+        MapCatalog::global().lookup(&self.map).name
     }
 
     pub fn date(&self) -> DateTime<Utc> {
@@ -97,8 +130,8 @@ impl Info {
 
 #[derive(Debug, Clone)]
 pub struct Round {
-    pub start_time: u64,
     pub winner: Option<TeamId>,
+    pub end: RoundEnd,
     pub first_cap: TeamId,
     pub length: u32,
     pub team: Teams,
@@ -106,6 +139,26 @@ pub struct Round {
     pub events: Vec<Event>,
 }
 
+/// Result of one team attacking in a stopwatch pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopwatchHalf {
+    /// The attacking team, `Other` if it can't be told from the round
+    pub attacker: TeamId,
+    /// Number of points capped by the attackers
+    pub points: u8,
+    /// Time into the half at which the last point was capped
+    pub time: u32,
+}
+
+/// An attack/defense pair in a stopwatch match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopwatchPair {
+    pub first: StopwatchHalf,
+    /// The second half is missing for a golden cap tiebreaker
+    pub second: Option<StopwatchHalf>,
+    pub winner: TeamId,
+}
+
 impl From<RawLog> for NormalizedLog {
     fn from(raw: RawLog) -> Self {
         let info = Info {
@@ -141,7 +194,6 @@ impl From<RawLog> for NormalizedLog {
         let teams = raw.teams.or(raw.info.teams).unwrap_or_default();
 
         let mut normalized = NormalizedLog {
-            version: raw.version,
             length: raw.length,
             teams,
             players: raw.players,
@@ -154,12 +206,12 @@ impl From<RawLog> for NormalizedLog {
             chat: raw.chat,
             info,
             kill_streaks: raw.kill_streaks.unwrap_or_default(),
+            stopwatch_pairs: Vec::new(),
+            corrections: Vec::new(),
         };
 
-        normalize_stopwatch_events(&mut normalized);
-        filter_double_wins(&mut normalized);
-        normalize_event_times(&mut normalized);
-        normalize_stopwatch_score(&mut normalized);
+        normalized.corrections = apply_passes(&mut normalized);
+        classify_round_ends(&mut normalized);
 
         normalized
     }
@@ -179,8 +231,8 @@ impl From<crate::raw::Round> for Round {
         let team = raw.team.or(raw.flat_team).unwrap_or_default();
 
         Round {
-            start_time: raw.start_time,
             winner: raw.winner,
+            end: RoundEnd::default(),
             first_cap,
             length: raw.length,
             team,
@@ -191,154 +243,7 @@ impl From<crate::raw::Round> for Round {
 }
 
 pub fn map_is_stopwatch(map: &str) -> bool {
-    map.starts_with("pl_")
-        || map.starts_with("cp_steel")
-        || map.starts_with("cp_gravelpit")
-        || map.starts_with("cp_dustbowl")
-        || map.starts_with("cp_egypt")
-        || map.starts_with("cp_degrootkeep")
-        || map.starts_with("cp_gorge")
-        || map.starts_with("cp_junction")
-        || map.starts_with("cp_mossrock")
-        || map.starts_with("cp_manor")
-        || map.starts_with("cp_snowplow")
-        || map.starts_with("cp_alloy")
-}
-
-/// Add missing round wins for 2nd round blue win
-fn normalize_stopwatch_events(log: &mut NormalizedLog) {
-    if map_is_stopwatch(&log.info.map)
-        && log.rounds.len() >= 2
-        && log.rounds[1].winner == Some(TeamId::Blue)
-    {
-        let first_half_rounds = get_round_point_capped(&log.rounds[0]);
-        let second_half_rounds = get_round_point_capped(&log.rounds[1]);
-        let second_half_end_time = get_round_end_time(&log.rounds[1]);
-
-        // attackers won 2nd round so they have to have at least the same number of point caps
-        // however some old demos dont properly include the last cap so we add them
-        if second_half_rounds < first_half_rounds {
-            let last_event = log.rounds[1].events.pop();
-            log.rounds[1].events.push(Event::PointCap {
-                time: second_half_end_time,
-                team: Some(TeamId::Blue),
-                point: first_half_rounds,
-            });
-            if let Some(last_event) = last_event {
-                log.rounds[1].events.push(last_event);
-            }
-        }
-    }
-}
-
-fn filter_double_wins(log: &mut NormalizedLog) {
-    for round in log.rounds.iter_mut() {
-        if let Some(index) = round
-            .events
-            .iter()
-            .enumerate()
-            .find(|(_index, event)| matches!(event, Event::RoundWin { .. }))
-            .map(|(index, _event)| index)
-        {
-            round.events.truncate(index + 1);
-        }
-    }
-}
-
-fn get_round_end_time(round: &Round) -> u32 {
-    round
-        .events
-        .iter()
-        .filter_map(|event| match event {
-            Event::RoundWin { time, .. } => Some(*time),
-            _ => None,
-        })
-        .last()
-        .unwrap_or_default()
-}
-
-fn get_first_event_time(round: &Round) -> u32 {
-    round
-        .events
-        .iter()
-        .map(|event| event.time())
-        .last()
-        .unwrap_or_default()
-}
-
-fn get_round_point_capped(round: &Round) -> u8 {
-    round
-        .events
-        .iter()
-        .filter_map(|event| match event {
-            Event::PointCap { point, .. } => Some(*point),
-            _ => None,
-        })
-        .last()
-        .unwrap_or_default()
-}
-
-/// Old logs have event times reset each round, newer ones keep counting
-fn normalize_event_times(log: &mut NormalizedLog) {
-    let mut prev_round_end_time = 0;
-    for round in log.rounds.iter_mut() {
-        if get_first_event_time(round) < prev_round_end_time {
-            round.events.iter_mut().for_each(|event| match event {
-                Event::PointCap { time, .. } => *time += prev_round_end_time,
-                Event::Charge { time, .. } => *time += prev_round_end_time,
-                Event::Drop { time, .. } => *time += prev_round_end_time,
-                Event::MedicDeath { time, .. } => *time += prev_round_end_time,
-                Event::RoundWin { time, .. } => *time += prev_round_end_time,
-                Event::Other => {}
-            });
-        }
-        prev_round_end_time = get_round_end_time(round);
-    }
-}
-
-fn get_last_cap_time(round: &Round) -> u32 {
-    round
-        .events
-        .iter()
-        .filter_map(|event| match event {
-            Event::PointCap { time, .. } => Some(*time),
-            _ => None,
-        })
-        .last()
-        .unwrap_or_default()
-}
-
-/// Apply modern ad scoring to old demos
-fn normalize_stopwatch_score(log: &mut NormalizedLog) {
-    if !log.info.ad_scoring && map_is_stopwatch(&log.info.map) && log.rounds.len() == 2 {
-        let first_half_capped = get_round_point_capped(&log.rounds[0]);
-        let second_half_capped = get_round_point_capped(&log.rounds[1]);
-
-        // "blue" is the team that attacked first
-        match first_half_capped.cmp(&second_half_capped) {
-            Ordering::Greater => {
-                log.teams.blue.score = 1;
-                log.teams.red.score = 0;
-            }
-            Ordering::Less => {
-                log.teams.blue.score = 0;
-                log.teams.red.score = 1;
-            }
-            Ordering::Equal => {
-                let first_half_cap_time = get_last_cap_time(&log.rounds[0]);
-                let second_half_cap_time = get_last_cap_time(&log.rounds[1])
-                    .saturating_sub(get_round_end_time(&log.rounds[0]));
-
-                if first_half_cap_time < second_half_cap_time {
-                    log.teams.blue.score = 1;
-                    log.teams.red.score = 0;
-                } else {
-                    log.teams.blue.score = 0;
-                    log.teams.red.score = 1;
-                }
-            }
-        }
-    }
+    MapCatalog::global().lookup(map).stopwatch
 }
 
 #[cfg(test)]
@@ -352,13 +257,14 @@ mod tests {
         serde_json::from_str(&content).unwrap()
     }
 
-    #[test_case("134389.json", 0, 1)]
-    #[test_case("550237.json", 1, 0)]
-    fn test_normalize_stopwatch_score(file: &str, blue: u32, red: u32) {
+    #[test_case("134389.json", 0, 1, TeamId::Red)]
+    #[test_case("550237.json", 1, 0, TeamId::Blue)]
+    fn test_normalize_stopwatch_score(file: &str, blue: u32, red: u32, winner: TeamId) {
         let parsed = parse(file);
 
         assert_eq!(parsed.teams.blue.score, blue);
         assert_eq!(parsed.teams.red.score, red);
+        assert_eq!(parsed.winner(), winner);
     }
 
     #[test_case("1.json")]
@@ -395,4 +301,79 @@ mod tests {
             );
         }
     }
+
+    #[test_case("1.json", &[RoundEnd::Cap, RoundEnd::Cap, RoundEnd::Cap, RoundEnd::Cap, RoundEnd::Cap, RoundEnd::Cap, RoundEnd::Cap, RoundEnd::Forfeit])]
+    #[test_case("114840.json", &[RoundEnd::Cap, RoundEnd::Cap, RoundEnd::Stalemate, RoundEnd::Stalemate])]
+    #[test_case("134389.json", &[RoundEnd::Cap, RoundEnd::Cap])]
+    #[test_case("550237.json", &[RoundEnd::Cap, RoundEnd::TimeLimit])]
+    #[test_case("2522305.json", &[RoundEnd::Cap, RoundEnd::Cap])]
+    fn test_round_end(file: &str, expected: &[RoundEnd]) {
+        let parsed = parse(file);
+
+        let ends: Vec<RoundEnd> = parsed.rounds.iter().map(|round| round.end).collect();
+        assert_eq!(expected, ends.as_slice());
+    }
+
+    #[test_case("1.json", &[
+        ("event_times", Some(1)),
+        ("event_times", Some(2)),
+        ("event_times", Some(3)),
+        ("event_times", Some(4)),
+        ("event_times", Some(5)),
+        ("event_times", Some(6)),
+        ("event_times", Some(7)),
+    ])]
+    #[test_case("114840.json", &[
+        ("filter_double_wins", Some(2)),
+        ("filter_double_wins", Some(3)),
+        ("event_times", Some(3)),
+    ])]
+    #[test_case("134389.json", &[("stopwatch_events", Some(1)), ("stopwatch_score", None)])]
+    #[test_case("550237.json", &[("stopwatch_score", None)])]
+    #[test_case("2522305.json", &[("stopwatch_events", Some(1))])]
+    fn test_corrections(file: &str, expected: &[(&str, Option<usize>)]) {
+        let parsed = parse(file);
+
+        let corrections: Vec<(&str, Option<usize>)> = parsed
+            .corrections
+            .iter()
+            .map(|correction| (correction.pass, correction.round))
+            .collect();
+        assert_eq!(expected, corrections.as_slice());
+    }
+
+    #[test_case("1.json", &[])]
+    #[test_case("134389.json", &[TeamId::Red])]
+    #[test_case("550237.json", &[TeamId::Blue])]
+    #[test_case("2522305.json", &[TeamId::Red])]
+    fn test_stopwatch_pairs(file: &str, expected: &[TeamId]) {
+        let parsed = parse(file);
+
+        let winners: Vec<TeamId> = parsed
+            .stopwatch_pairs
+            .iter()
+            .map(|pair| pair.winner)
+            .collect();
+        assert_eq!(expected, winners.as_slice());
+    }
+
+    #[test_case("1.json")]
+    #[test_case("134389.json")]
+    #[test_case("550237.json")]
+    #[test_case("3579548.json")]
+    fn test_is_starter(file: &str) {
+        let mut parsed = parse(file);
+
+        for steam_id in parsed.players.keys() {
+            assert!(parsed.is_starter(steam_id));
+        }
+
+        let (&steam_id, player) = parsed.players.iter().next().unwrap();
+        let mut substitute = player.clone();
+        substitute.class_stats.truncate(1);
+        substitute.class_stats[0].total_time = 120;
+        let substitute_id = SteamID::from(u64::from(steam_id) + 1);
+        parsed.players.insert(substitute_id, substitute);
+        assert!(!parsed.is_starter(&substitute_id));
+    }
 }
@@ -0,0 +1,137 @@
+use sqlx::postgres::PgListener;
+use std::cmp::min;
+use tokio::time::{sleep_until, timeout, timeout_at, Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Time without new notifications before starting a pass, so a burst of inserts is handled in one pass
+const DEBOUNCE: Duration = Duration::from_secs(5);
+/// Maximum time to keep waiting for a burst of notifications to end
+const MAX_DEBOUNCE: Duration = Duration::from_secs(60);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Waits for notifications about new raw logs, falling back to a timer if they don't arrive
+///
+/// The fetcher is expected to notify the channel after inserting into `logs_raw`, which can be done from a trigger:
+///
+/// ```sql
+/// CREATE FUNCTION notify_logs_raw() RETURNS TRIGGER AS $$
+/// BEGIN
+///     PERFORM pg_notify('logs_raw', NEW.id::TEXT);
+///     RETURN NEW;
+/// END; $$ LANGUAGE PLPGSQL;
+///
+/// CREATE TRIGGER logs_raw_notify AFTER INSERT ON logs_raw
+///     FOR EACH ROW EXECUTE FUNCTION notify_logs_raw();
+/// ```
+pub struct LogNotifier {
+    url: String,
+    channel: String,
+    listener: Option<PgListener>,
+    reconnect_delay: Duration,
+}
+
+impl LogNotifier {
+    pub fn new(url: &str, channel: &str) -> Self {
+        LogNotifier {
+            url: url.into(),
+            channel: channel.into(),
+            listener: None,
+            reconnect_delay: MIN_RECONNECT_DELAY,
+        }
+    }
+
+    async fn connect(&self) -> Result<PgListener, sqlx::Error> {
+        let mut listener = PgListener::connect(&self.url).await?;
+        listener.listen(&self.channel).await?;
+        Ok(listener)
+    }
+
+    /// Wait until new raw logs are announced, or the fallback interval has passed
+    pub async fn wait(&mut self, fallback: Duration) {
+        let deadline = Instant::now() + fallback;
+        loop {
+            let listener = match &mut self.listener {
+                Some(listener) => listener,
+                None => match self.connect().await {
+                    Ok(listener) => {
+                        info!(channel = display(&self.channel), "listening for new logs");
+                        self.reconnect_delay = MIN_RECONNECT_DELAY;
+                        self.listener.insert(listener)
+                    }
+                    Err(e) => {
+                        warn!(
+                            error = display(e),
+                            retry = debug(self.reconnect_delay),
+                            "failed to listen for new logs"
+                        );
+                        let retry = Instant::now() + self.reconnect_delay;
+                        self.reconnect_delay = next_reconnect_delay(self.reconnect_delay);
+                        if retry >= deadline {
+                            sleep_until(deadline).await;
+                            return;
+                        }
+                        sleep_until(retry).await;
+                        continue;
+                    }
+                },
+            };
+
+            match timeout_at(deadline, listener.try_recv()).await {
+                Err(_) => return,
+                Ok(Ok(Some(notification))) => {
+                    debug!(payload = notification.payload(), "new log notification");
+                    self.debounce().await;
+                    return;
+                }
+                // the listener reconnects by itself, but notifications sent in the meantime are lost
+                Ok(Ok(None)) => {
+                    warn!("lost connection while listening for new logs");
+                    return;
+                }
+                Ok(Err(e)) => {
+                    warn!(error = display(e), "error while listening for new logs");
+                    self.listener = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Wait until no new notifications arrive for a while
+    async fn debounce(&mut self) {
+        let start = Instant::now();
+        while let Some(listener) = &mut self.listener {
+            let wait = min(DEBOUNCE, MAX_DEBOUNCE.saturating_sub(start.elapsed()));
+            match timeout(wait, listener.try_recv()).await {
+                Ok(Ok(Some(_))) if start.elapsed() < MAX_DEBOUNCE => {}
+                Ok(Err(e)) => {
+                    warn!(error = display(e), "error while listening for new logs");
+                    self.listener = None;
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+fn next_reconnect_delay(delay: Duration) -> Duration {
+    min(delay * 2, MAX_RECONNECT_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconnect_delay() {
+        assert_eq!(
+            Duration::from_secs(2),
+            next_reconnect_delay(MIN_RECONNECT_DELAY)
+        );
+        assert_eq!(
+            MAX_RECONNECT_DELAY,
+            next_reconnect_delay(MAX_RECONNECT_DELAY)
+        );
+    }
+}
@@ -0,0 +1,496 @@
+use crate::data::{MapType, RoundEnd, TeamId};
+use crate::maps::{MapCatalog, MapInfo};
+use crate::normalized::{
+    map_is_stopwatch, Event, NormalizedLog, Round, StopwatchHalf, StopwatchPair,
+};
+use std::cmp::Ordering;
+
+/// A single change made to a log by a normalization pass
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Correction {
+    pub pass: &'static str,
+    pub round: Option<usize>,
+    pub description: String,
+}
+
+impl Correction {
+    fn new(pass: &dyn NormalizationPass, round: Option<usize>, description: String) -> Self {
+        Correction {
+            pass: pass.name(),
+            round,
+            description,
+        }
+    }
+}
+
+pub trait NormalizationPass: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Apply the pass to the log, returning the list of corrections that were made
+    fn apply(&self, log: &mut NormalizedLog) -> Vec<Correction>;
+}
+
+/// All normalization passes, in the order they are applied
+pub static PASSES: &[&dyn NormalizationPass] = &[
+    &StopwatchEvents,
+    &FilterDoubleWins,
+    &EventTimes,
+    &StopwatchScore,
+];
+
+pub fn apply_passes(log: &mut NormalizedLog) -> Vec<Correction> {
+    PASSES.iter().flat_map(|pass| pass.apply(log)).collect()
+}
+
+/// Add missing round wins for 2nd round blue win, in every attack/defense pair
+pub struct StopwatchEvents;
+
+impl NormalizationPass for StopwatchEvents {
+    fn name(&self) -> &'static str {
+        "stopwatch_events"
+    }
+
+    fn apply(&self, log: &mut NormalizedLog) -> Vec<Correction> {
+        let mut corrections = Vec::new();
+        if !map_is_stopwatch(&log.info.map) {
+            return corrections;
+        }
+        for second in (1..log.rounds.len()).step_by(2) {
+            if log.rounds[second].winner != Some(TeamId::Blue) {
+                continue;
+            }
+            let first_half_rounds = get_round_point_capped(&log.rounds[second - 1]);
+            let second_half_rounds = get_round_point_capped(&log.rounds[second]);
+            let second_half_end_time = get_round_end_time(&log.rounds[second]);
+
+            // attackers won 2nd round so they have to have at least the same number of point caps
+            // however some old demos dont properly include the last cap so we add them
+            if second_half_rounds < first_half_rounds {
+                let events = &mut log.rounds[second].events;
+                let last_event = events.pop();
+                events.push(Event::PointCap {
+                    time: second_half_end_time,
+                    team: Some(TeamId::Blue),
+                    point: first_half_rounds,
+                });
+                if let Some(last_event) = last_event {
+                    events.push(last_event);
+                }
+                corrections.push(Correction::new(
+                    self,
+                    Some(second),
+                    format!(
+                        "added synthetic PointCap for point {} at t={}",
+                        first_half_rounds, second_half_end_time
+                    ),
+                ));
+            }
+        }
+        corrections
+    }
+}
+
+pub struct FilterDoubleWins;
+
+impl NormalizationPass for FilterDoubleWins {
+    fn name(&self) -> &'static str {
+        "filter_double_wins"
+    }
+
+    fn apply(&self, log: &mut NormalizedLog) -> Vec<Correction> {
+        let mut corrections = Vec::new();
+        for (num, round) in log.rounds.iter_mut().enumerate() {
+            if let Some(index) = round
+                .events
+                .iter()
+                .enumerate()
+                .find(|(_index, event)| matches!(event, Event::RoundWin { .. }))
+                .map(|(index, _event)| index)
+            {
+                let removed = round.events.len() - (index + 1);
+                if removed > 0 {
+                    round.events.truncate(index + 1);
+                    corrections.push(Correction::new(
+                        self,
+                        Some(num),
+                        format!("removed {} events after the first RoundWin", removed),
+                    ));
+                }
+            }
+        }
+        corrections
+    }
+}
+
+/// Old logs have event times reset each round, newer ones keep counting
+pub struct EventTimes;
+
+impl NormalizationPass for EventTimes {
+    fn name(&self) -> &'static str {
+        "event_times"
+    }
+
+    fn apply(&self, log: &mut NormalizedLog) -> Vec<Correction> {
+        let mut corrections = Vec::new();
+        let mut prev_round_end_time = 0;
+        for (num, round) in log.rounds.iter_mut().enumerate() {
+            if get_first_event_time(round) < prev_round_end_time {
+                shift_event_times(round, prev_round_end_time);
+                corrections.push(Correction::new(
+                    self,
+                    Some(num),
+                    format!("shifted event times by {}s", prev_round_end_time),
+                ));
+            }
+            prev_round_end_time = get_round_end_time(round);
+        }
+        corrections
+    }
+}
+
+pub(crate) fn shift_event_times(round: &mut Round, offset: u32) {
+    round.events.iter_mut().for_each(|event| match event {
+        Event::PointCap { time, .. } => *time += offset,
+        Event::Charge { time, .. } => *time += offset,
+        Event::Drop { time, .. } => *time += offset,
+        Event::MedicDeath { time, .. } => *time += offset,
+        Event::RoundWin { time, .. } => *time += offset,
+        Event::Other => {}
+    });
+}
+
+/// Score stopwatch logs per attack/defense pair and apply modern ad scoring to old demos
+pub struct StopwatchScore;
+
+impl NormalizationPass for StopwatchScore {
+    fn name(&self) -> &'static str {
+        "stopwatch_score"
+    }
+
+    fn apply(&self, log: &mut NormalizedLog) -> Vec<Correction> {
+        let mut corrections = Vec::new();
+        if !map_is_stopwatch(&log.info.map) || log.rounds.len() < 2 {
+            return corrections;
+        }
+
+        let halves: Vec<StopwatchHalf> = log
+            .rounds
+            .iter()
+            .enumerate()
+            .map(|(num, round)| {
+                let start = match num {
+                    0 => 0,
+                    _ => get_round_end_time(&log.rounds[num - 1]),
+                };
+                StopwatchHalf {
+                    attacker: get_round_attacker(round),
+                    points: get_round_point_capped(round),
+                    time: get_last_cap_time(round).saturating_sub(start),
+                }
+            })
+            .collect();
+
+        log.stopwatch_pairs = halves
+            .chunks(2)
+            .enumerate()
+            .map(|(pair, halves)| match halves {
+                [first, second] => StopwatchPair {
+                    first: first.clone(),
+                    second: Some(second.clone()),
+                    winner: pair_winner(first, second),
+                },
+                // a lone half at the end is a golden cap tiebreaker, which is won by whoever wins the round
+                [first] => StopwatchPair {
+                    first: first.clone(),
+                    second: None,
+                    winner: log.rounds[pair * 2].winner.unwrap_or_default(),
+                },
+                _ => unreachable!(),
+            })
+            .collect();
+
+        if !log.info.ad_scoring {
+            let original = (log.teams.blue.score, log.teams.red.score);
+            log.teams.blue.score = log
+                .stopwatch_pairs
+                .iter()
+                .filter(|pair| pair.winner == TeamId::Blue)
+                .count() as u32;
+            log.teams.red.score = log
+                .stopwatch_pairs
+                .iter()
+                .filter(|pair| pair.winner == TeamId::Red)
+                .count() as u32;
+
+            let score = (log.teams.blue.score, log.teams.red.score);
+            if score != original {
+                corrections.push(Correction::new(
+                    self,
+                    None,
+                    format!(
+                        "changed score from {}-{} to {}-{} (blue-red)",
+                        original.0, original.1, score.0, score.1
+                    ),
+                ));
+            }
+        }
+        corrections
+    }
+}
+
+/// The team that capped more points or capped them faster, `Other` for a tie
+fn pair_winner(first: &StopwatchHalf, second: &StopwatchHalf) -> TeamId {
+    // the teams swap sides, so either half tells who attacked first
+    let first_attacker = match (first.attacker, second.attacker) {
+        (TeamId::Other, second) => second.opponent(),
+        (first, _) => first,
+    };
+    let ordering = match first.points.cmp(&second.points) {
+        Ordering::Equal => second.time.cmp(&first.time),
+        ordering => ordering,
+    };
+    match ordering {
+        Ordering::Greater => first_attacker,
+        Ordering::Less => first_attacker.opponent(),
+        Ordering::Equal => TeamId::Other,
+    }
+}
+
+/// The team that capped points in the round, or the team that lost the round if nothing was capped
+fn get_round_attacker(round: &Round) -> TeamId {
+    let capper = round.events.iter().find_map(|event| match event {
+        Event::PointCap {
+            team: Some(team @ (TeamId::Red | TeamId::Blue)),
+            ..
+        } => Some(*team),
+        _ => None,
+    });
+    let winner = round.events.iter().find_map(|event| match event {
+        Event::RoundWin {
+            team: Some(team @ (TeamId::Red | TeamId::Blue)),
+            ..
+        } => Some(*team),
+        _ => None,
+    });
+    match (capper, winner) {
+        (Some(team), _) => team,
+        (None, Some(team)) => team.opponent(),
+        (None, None) => TeamId::Other,
+    }
+}
+
+/// Maximum time between the last cap and the round win for the win to count as won by that cap
+const CAP_WIN_MARGIN: u32 = 5;
+
+/// Default round timer for non attack/defense maps
+const ROUND_TIME_LIMIT: u32 = 600;
+
+/// Classify how each round ended, after the passes fixed the round events
+///
+/// This only adds information to the log, so it isn't a normalization pass and doesn't report corrections
+pub fn classify_round_ends(log: &mut NormalizedLog) {
+    let map = MapCatalog::global().lookup(&log.info.map);
+    for round in log.rounds.iter_mut() {
+        round.end = classify_round_end(round, &map);
+    }
+}
+
+fn classify_round_end(round: &Round, map: &MapInfo) -> RoundEnd {
+    let win = round.events.iter().find_map(|event| match event {
+        Event::RoundWin { time, team } => Some((*time, *team)),
+        _ => None,
+    });
+    let (win_time, winner) = match win {
+        Some((time, Some(team @ (TeamId::Red | TeamId::Blue)))) => (time, team),
+        Some(_) => return RoundEnd::Stalemate,
+        None => return RoundEnd::Truncated,
+    };
+    let last_cap = round.events.iter().rev().find_map(|event| match event {
+        Event::PointCap { time, team, .. } => Some((*time, *team)),
+        _ => None,
+    });
+    let winner_capped_last = matches!(last_cap, Some((_, Some(team))) if team == winner);
+
+    match (map.map_type, last_cap) {
+        (_, Some((cap_time, _)))
+            if winner_capped_last && win_time.saturating_sub(cap_time) <= CAP_WIN_MARGIN =>
+        {
+            RoundEnd::Cap
+        }
+        // koth rounds are won by holding the point until the timer runs out
+        (MapType::Koth, _) if winner_capped_last => RoundEnd::Cap,
+        // objectives that aren't logged as point caps
+        (
+            MapType::Ctf
+            | MapType::Passtime
+            | MapType::PlayerDestruction
+            | MapType::SpecialDelivery
+            | MapType::Arena,
+            _,
+        ) => RoundEnd::Cap,
+        // defenders win when the attackers run out of time
+        _ if map.stopwatch => RoundEnd::TimeLimit,
+        _ if round.length >= ROUND_TIME_LIMIT => RoundEnd::TimeLimit,
+        _ => RoundEnd::Forfeit,
+    }
+}
+
+fn get_round_end_time(round: &Round) -> u32 {
+    round
+        .events
+        .iter()
+        .filter_map(|event| match event {
+            Event::RoundWin { time, .. } => Some(*time),
+            _ => None,
+        })
+        .next_back()
+        .unwrap_or_default()
+}
+
+fn get_first_event_time(round: &Round) -> u32 {
+    round
+        .events
+        .iter()
+        .map(|event| event.time())
+        .next()
+        .unwrap_or_default()
+}
+
+fn get_round_point_capped(round: &Round) -> u8 {
+    round
+        .events
+        .iter()
+        .filter_map(|event| match event {
+            Event::PointCap { point, .. } => Some(*point),
+            _ => None,
+        })
+        .next_back()
+        .unwrap_or_default()
+}
+
+fn get_last_cap_time(round: &Round) -> u32 {
+    round
+        .events
+        .iter()
+        .filter_map(|event| match event {
+            Event::PointCap { time, .. } => Some(*time),
+            _ => None,
+        })
+        .next_back()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn parse(file: &str) -> NormalizedLog {
+        let content = fs::read_to_string(format!("tests/data/{}", file)).unwrap();
+        serde_json::from_str(&content).unwrap()
+    }
+
+    fn append_rounds(log: &mut NormalizedLog, rounds: &[Round]) {
+        let offset = get_round_end_time(log.rounds.last().unwrap());
+        for round in rounds {
+            let mut round = round.clone();
+            shift_event_times(&mut round, offset);
+            log.rounds.push(round);
+        }
+    }
+
+    #[test]
+    fn test_stopwatch_events_multiple_pairs() {
+        let mut log = parse("550237.json");
+        // parsing already adds the missing cap, so remove it again
+        let mut missing_cap = parse("134389.json").rounds;
+        let events = &mut missing_cap[1].events;
+        let cap = events
+            .iter()
+            .rposition(|event| matches!(event, Event::PointCap { .. }))
+            .unwrap();
+        events.remove(cap);
+        append_rounds(&mut log, &missing_cap);
+        append_rounds(&mut log, &missing_cap);
+
+        let corrections = StopwatchEvents.apply(&mut log);
+
+        let rounds: Vec<Option<usize>> = corrections.iter().map(|c| c.round).collect();
+        assert_eq!(vec![Some(3), Some(5)], rounds);
+        for round in [&log.rounds[3], &log.rounds[5]] {
+            assert_eq!(4, get_round_point_capped(round));
+            assert!(matches!(round.events.last(), Some(Event::RoundWin { .. })));
+        }
+        assert_eq!(3, get_round_point_capped(&log.rounds[1]));
+    }
+
+    #[test]
+    fn test_stopwatch_multiple_pairs() {
+        let mut log = parse("550237.json");
+        let first_pair = log.rounds.clone();
+        append_rounds(&mut log, &parse("134389.json").rounds);
+        append_rounds(&mut log, &first_pair[0..1]);
+
+        StopwatchScore.apply(&mut log);
+
+        let winners: Vec<TeamId> = log.stopwatch_pairs.iter().map(|pair| pair.winner).collect();
+        assert_eq!(vec![TeamId::Blue, TeamId::Red, TeamId::Blue], winners);
+        assert_eq!(
+            StopwatchHalf {
+                attacker: TeamId::Blue,
+                points: 4,
+                time: 778
+            },
+            log.stopwatch_pairs[1].first
+        );
+        assert_eq!(None, log.stopwatch_pairs[2].second);
+        assert_eq!(2, log.teams.blue.score);
+        assert_eq!(1, log.teams.red.score);
+    }
+
+    #[test]
+    fn test_stopwatch_red_attacks_first() {
+        let mut log = parse("550237.json");
+        // swap the sides so red attacks first
+        for round in log.rounds.iter_mut() {
+            for event in round.events.iter_mut() {
+                if let Event::PointCap { team, .. } | Event::RoundWin { team, .. } = event {
+                    *team = team.map(TeamId::opponent);
+                }
+            }
+        }
+
+        StopwatchScore.apply(&mut log);
+
+        assert_eq!(TeamId::Red, log.stopwatch_pairs[0].first.attacker);
+        assert_eq!(TeamId::Red, log.stopwatch_pairs[0].winner);
+        assert_eq!(0, log.teams.blue.score);
+        assert_eq!(1, log.teams.red.score);
+    }
+
+    #[test]
+    fn test_stopwatch_tie() {
+        let half = |attacker, points, time| StopwatchHalf {
+            attacker,
+            points,
+            time,
+        };
+        assert_eq!(
+            TeamId::Other,
+            pair_winner(&half(TeamId::Red, 3, 400), &half(TeamId::Blue, 3, 400))
+        );
+        assert_eq!(
+            TeamId::Other,
+            pair_winner(&half(TeamId::Red, 0, 0), &half(TeamId::Blue, 0, 0))
+        );
+        // the attacker of a half without caps or winner follows from the other half
+        assert_eq!(
+            TeamId::Red,
+            pair_winner(&half(TeamId::Other, 2, 0), &half(TeamId::Blue, 1, 0))
+        );
+        assert_eq!(
+            TeamId::Blue,
+            pair_winner(&half(TeamId::Red, 3, 500), &half(TeamId::Blue, 3, 400))
+        );
+    }
+}
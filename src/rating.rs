@@ -0,0 +1,495 @@
+use crate::data::{Class, GameMode, TeamId};
+use crate::health::Health;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use tracing::{info, instrument};
+
+/// Rating of a player the first time they play a class in a game mode
+pub const INITIAL_RATING: f64 = 1500.0;
+
+/// Rating change factor for new players, so their rating converges quickly
+const MAX_K: f64 = 40.0;
+
+/// Rating change factor for players with enough games for a stable rating
+const MIN_K: f64 = 16.0;
+
+/// Number of games after which a rating is considered stable
+const STABLE_GAMES: i32 = 30;
+
+/// Number of logs rated in a single transaction
+const BATCH_SIZE: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct RatingKey {
+    pub steam_id: i64,
+    pub game_mode: GameMode,
+    pub class: Class,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rating {
+    pub rating: f64,
+    pub games: i32,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Rating {
+            rating: INITIAL_RATING,
+            games: 0,
+        }
+    }
+}
+
+impl Rating {
+    fn k_factor(&self) -> f64 {
+        let progress = self.games.min(STABLE_GAMES) as f64 / STABLE_GAMES as f64;
+        MAX_K - (MAX_K - MIN_K) * progress
+    }
+}
+
+/// A player taking part in a rated log, on their main class for the log
+#[derive(Debug, Clone)]
+pub struct Participant {
+    pub steam_id: i64,
+    pub team: TeamId,
+    pub class: Class,
+    pub is_winner: bool,
+}
+
+/// The outcome of a log used for rating
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogResult {
+    pub red_rounds: u32,
+    pub blue_rounds: u32,
+    pub winner: TeamId,
+}
+
+impl LogResult {
+    /// Share of the result won by the team, from the rounds won or the log winner if no rounds were decided
+    fn score(&self, team: TeamId, is_winner: bool) -> f64 {
+        let (won, lost) = match team {
+            TeamId::Red => (self.red_rounds, self.blue_rounds),
+            _ => (self.blue_rounds, self.red_rounds),
+        };
+        if won + lost > 0 {
+            won as f64 / (won + lost) as f64
+        } else if is_winner {
+            1.0
+        } else if self.winner == TeamId::Other {
+            0.5
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RatingChange {
+    pub key: RatingKey,
+    pub before: f64,
+    pub after: f64,
+}
+
+fn expected_score(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+/// Update the ratings of all participants of a log, each player is rated against the average of the other team
+pub fn rate_log(
+    ratings: &mut HashMap<RatingKey, Rating>,
+    game_mode: GameMode,
+    participants: &[Participant],
+    result: LogResult,
+) -> Vec<RatingChange> {
+    let key = |participant: &Participant| RatingKey {
+        steam_id: participant.steam_id,
+        game_mode,
+        class: participant.class,
+    };
+    let team_rating = |team: TeamId| {
+        let team_ratings: Vec<f64> = participants
+            .iter()
+            .filter(|participant| participant.team == team)
+            .map(|participant| {
+                ratings
+                    .get(&key(participant))
+                    .copied()
+                    .unwrap_or_default()
+                    .rating
+            })
+            .collect();
+        (!team_ratings.is_empty())
+            .then(|| team_ratings.iter().sum::<f64>() / team_ratings.len() as f64)
+    };
+    let (Some(red), Some(blue)) = (team_rating(TeamId::Red), team_rating(TeamId::Blue)) else {
+        return Vec::new();
+    };
+
+    participants
+        .iter()
+        .filter_map(|participant| {
+            let (team, opponent) = match participant.team {
+                TeamId::Red => (red, blue),
+                TeamId::Blue => (blue, red),
+                TeamId::Other => return None,
+            };
+            let key = key(participant);
+            let rating = ratings.entry(key).or_default();
+            let before = rating.rating;
+            let score = result.score(participant.team, participant.is_winner);
+            rating.rating += rating.k_factor() * (score - expected_score(team, opponent));
+            rating.games += 1;
+            Some(RatingChange {
+                key,
+                before,
+                after: rating.rating,
+            })
+        })
+        .collect()
+}
+
+/// Rate all logs that haven't been rated yet, replaying the ratings from the earliest of them if later logs are already rated
+#[instrument(skip(pool, health))]
+pub async fn update_ratings(pool: &PgPool, health: &Health) -> Result<(), sqlx::Error> {
+    // logs that were invalidated after being rated still need their rating changes undone
+    let replay_from = sqlx::query!(
+        r#"SELECT id FROM logs AS start
+        WHERE NOT rated AND (is_valid OR EXISTS(SELECT 1 FROM rating_history WHERE log_id = start.id))
+        AND EXISTS(SELECT 1 FROM logs WHERE rated AND (date, id) > (start.date, start.id))
+        ORDER BY date, id LIMIT 1"#
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(start) = replay_from {
+        replay_ratings(pool, start.id).await?;
+        health.progress();
+    }
+    rate_unrated_logs(pool, health).await
+}
+
+/// Undo the ratings of the log and every log after it, so they are rated again in date order
+#[instrument(skip(pool))]
+async fn replay_ratings(pool: &PgPool, start: i32) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let replayed: Vec<i32> = sqlx::query!(
+        "UPDATE logs SET rated = false \
+        WHERE (date, id) >= (SELECT date, id FROM logs WHERE id = $1) RETURNING id",
+        start
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|log| log.id)
+    .collect();
+    let steam_ids: Vec<i64> = sqlx::query!(
+        "DELETE FROM player_ratings WHERE (steam_id, game_mode, class) IN \
+            (SELECT steam_id, game_mode, class FROM rating_history WHERE log_id = ANY($1)) \
+        RETURNING steam_id",
+        &replayed
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|rating| rating.steam_id)
+    .collect();
+    sqlx::query!(
+        "DELETE FROM rating_history WHERE log_id = ANY($1)",
+        &replayed
+    )
+    .execute(&mut *tx)
+    .await?;
+    // restore the ratings as they were after the last log before the replay
+    sqlx::query!(
+        "INSERT INTO player_ratings(steam_id, game_mode, class, rating, games, last_log_id) \
+        SELECT DISTINCT ON (steam_id, game_mode, class) steam_id, game_mode, class, rating_after, \
+            COUNT(*) OVER (PARTITION BY steam_id, game_mode, class), log_id \
+        FROM rating_history \
+        WHERE steam_id = ANY($1) AND NOT EXISTS( \
+            SELECT 1 FROM player_ratings \
+            WHERE (player_ratings.steam_id, player_ratings.game_mode, player_ratings.class) \
+                = (rating_history.steam_id, rating_history.game_mode, rating_history.class) \
+        ) \
+        ORDER BY steam_id, game_mode, class, id DESC",
+        &steam_ids
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    info!(count = replayed.len(), "replaying ratings");
+    Ok(())
+}
+
+async fn rate_unrated_logs(pool: &PgPool, health: &Health) -> Result<(), sqlx::Error> {
+    let mut ratings: HashMap<RatingKey, Rating> = HashMap::new();
+    loop {
+        let mut tx = pool.begin().await?;
+        let logs = sqlx::query!(
+            r#"SELECT id, game_mode as "game_mode: GameMode", winner as "winner!: TeamId"
+            FROM logs WHERE is_valid AND NOT rated ORDER BY date, id LIMIT $1"#,
+            BATCH_SIZE
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        if logs.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<i32> = logs.iter().map(|log| log.id).collect();
+
+        let mut participants: HashMap<i32, Vec<Participant>> = HashMap::new();
+        let players = sqlx::query!(
+            r#"SELECT log_id, steam_id, team as "team: TeamId", is_winner as "is_winner!",
+                (SELECT type FROM class_stats WHERE player_id = players.id ORDER BY time DESC LIMIT 1) as "class: Class"
+            FROM players
+            WHERE log_id = ANY($1) AND is_valid AND played_enough"#,
+            &ids
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for player in players {
+            let Some(class) = player.class.filter(|class| *class != Class::Unknown) else {
+                continue;
+            };
+            participants
+                .entry(player.log_id)
+                .or_default()
+                .push(Participant {
+                    steam_id: player.steam_id,
+                    team: player.team,
+                    class,
+                    is_winner: player.is_winner,
+                });
+        }
+
+        let mut results: HashMap<i32, LogResult> = HashMap::new();
+        let rounds = sqlx::query!(
+            r#"SELECT log_id,
+                COUNT(*) FILTER (WHERE winner = 'red') as "red!",
+                COUNT(*) FILTER (WHERE winner = 'blue') as "blue!"
+            FROM rounds WHERE log_id = ANY($1) GROUP BY log_id"#,
+            &ids
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for round in rounds {
+            results.insert(
+                round.log_id,
+                LogResult {
+                    red_rounds: round.red as u32,
+                    blue_rounds: round.blue as u32,
+                    winner: TeamId::Other,
+                },
+            );
+        }
+
+        for log in &logs {
+            health.progress();
+            let mut participants = participants.remove(&log.id).unwrap_or_default();
+            // players are rated in a fixed order so recomputing gives the same result
+            participants.sort_by_key(|participant| participant.steam_id);
+            load_ratings(&mut tx, &mut ratings, log.game_mode, &participants).await?;
+            let result = LogResult {
+                winner: log.winner,
+                ..results.get(&log.id).copied().unwrap_or_default()
+            };
+            let changes = rate_log(&mut ratings, log.game_mode, &participants, result);
+            store_changes(&mut tx, log.id, &ratings, &changes).await?;
+        }
+
+        sqlx::query!("UPDATE logs SET rated = true WHERE id = ANY($1)", &ids)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        info!(count = logs.len(), "rated logs");
+    }
+}
+
+async fn load_ratings(
+    tx: &mut Transaction<'_, Postgres>,
+    ratings: &mut HashMap<RatingKey, Rating>,
+    game_mode: GameMode,
+    participants: &[Participant],
+) -> Result<(), sqlx::Error> {
+    for participant in participants {
+        let key = RatingKey {
+            steam_id: participant.steam_id,
+            game_mode,
+            class: participant.class,
+        };
+        if ratings.contains_key(&key) {
+            continue;
+        }
+        let stored = sqlx::query!(
+            "SELECT rating, games FROM player_ratings WHERE steam_id = $1 AND game_mode = $2 AND class = $3",
+            key.steam_id,
+            key.game_mode as GameMode,
+            key.class as Class,
+        )
+        .fetch_optional(&mut **tx)
+        .await?;
+        if let Some(stored) = stored {
+            ratings.insert(
+                key,
+                Rating {
+                    rating: stored.rating,
+                    games: stored.games,
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn store_changes(
+    tx: &mut Transaction<'_, Postgres>,
+    log_id: i32,
+    ratings: &HashMap<RatingKey, Rating>,
+    changes: &[RatingChange],
+) -> Result<(), sqlx::Error> {
+    for change in changes {
+        let key = change.key;
+        let games = ratings.get(&key).copied().unwrap_or_default().games;
+        sqlx::query!(
+            "INSERT INTO rating_history(log_id, steam_id, game_mode, class, rating_before, rating_after)\
+                VALUES($1, $2, $3, $4, $5, $6)",
+            log_id,
+            key.steam_id,
+            key.game_mode as GameMode,
+            key.class as Class,
+            change.before,
+            change.after,
+        )
+        .execute(&mut **tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO player_ratings(steam_id, game_mode, class, rating, games, last_log_id)\
+                VALUES($1, $2, $3, $4, $5, $6) \
+                ON CONFLICT (steam_id, game_mode, class) DO UPDATE SET \
+                    rating = EXCLUDED.rating, games = EXCLUDED.games, last_log_id = EXCLUDED.last_log_id",
+            key.steam_id,
+            key.game_mode as GameMode,
+            key.class as Class,
+            change.after,
+            games,
+            log_id,
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn team(team: TeamId, first_id: i64, is_winner: bool) -> Vec<Participant> {
+        [Class::Scout, Class::Soldier, Class::Demoman, Class::Medic]
+            .iter()
+            .copied()
+            .enumerate()
+            .map(|(n, class)| Participant {
+                steam_id: first_id + n as i64,
+                team,
+                class,
+                is_winner,
+            })
+            .collect()
+    }
+
+    fn game(red_rounds: u32, blue_rounds: u32) -> (Vec<Participant>, LogResult) {
+        let mut participants = team(TeamId::Red, 0, red_rounds > blue_rounds);
+        participants.extend(team(TeamId::Blue, 10, blue_rounds > red_rounds));
+        let winner = match red_rounds.cmp(&blue_rounds) {
+            std::cmp::Ordering::Greater => TeamId::Red,
+            std::cmp::Ordering::Less => TeamId::Blue,
+            std::cmp::Ordering::Equal => TeamId::Other,
+        };
+        (
+            participants,
+            LogResult {
+                red_rounds,
+                blue_rounds,
+                winner,
+            },
+        )
+    }
+
+    fn rating(ratings: &HashMap<RatingKey, Rating>, steam_id: i64, class: Class) -> f64 {
+        ratings[&RatingKey {
+            steam_id,
+            game_mode: GameMode::Sixes,
+            class,
+        }]
+            .rating
+    }
+
+    #[test]
+    fn test_winner_gains() {
+        let mut ratings = HashMap::new();
+        let (participants, result) = game(5, 2);
+        let changes = rate_log(&mut ratings, GameMode::Sixes, &participants, result);
+
+        assert_eq!(8, changes.len());
+        assert!(rating(&ratings, 0, Class::Scout) > INITIAL_RATING);
+        assert!(rating(&ratings, 10, Class::Scout) < INITIAL_RATING);
+        // ratings are zero-sum between equally rated teams
+        let total: f64 = ratings.values().map(|rating| rating.rating).sum();
+        assert!((total - INITIAL_RATING * 8.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_margin() {
+        let mut close = HashMap::new();
+        let (participants, result) = game(5, 4);
+        rate_log(&mut close, GameMode::Sixes, &participants, result);
+
+        let mut wide = HashMap::new();
+        let (participants, result) = game(5, 0);
+        rate_log(&mut wide, GameMode::Sixes, &participants, result);
+
+        assert!(rating(&wide, 0, Class::Scout) > rating(&close, 0, Class::Scout));
+    }
+
+    #[test]
+    fn test_upset() {
+        let mut ratings = HashMap::new();
+        for _ in 0..10 {
+            let (participants, result) = game(5, 0);
+            rate_log(&mut ratings, GameMode::Sixes, &participants, result);
+        }
+        let favorite = rating(&ratings, 0, Class::Scout);
+        let underdog = rating(&ratings, 10, Class::Scout);
+
+        let (participants, result) = game(0, 5);
+        let changes = rate_log(&mut ratings, GameMode::Sixes, &participants, result);
+        let gain = changes
+            .iter()
+            .find(|change| change.key.steam_id == 10)
+            .map(|change| change.after - change.before)
+            .unwrap();
+
+        assert!(rating(&ratings, 0, Class::Scout) < favorite);
+        assert!(rating(&ratings, 10, Class::Scout) > underdog);
+        assert!(gain > MIN_K);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let run = || {
+            let mut ratings = HashMap::new();
+            for (red, blue) in [(5, 2), (1, 5), (3, 3), (4, 5)] {
+                let (participants, result) = game(red, blue);
+                rate_log(&mut ratings, GameMode::Sixes, &participants, result);
+            }
+            let mut ratings: Vec<(i64, f64)> = ratings
+                .into_iter()
+                .map(|(key, rating)| (key.steam_id, rating.rating))
+                .collect();
+            ratings.sort_by_key(|(steam_id, _)| *steam_id);
+            ratings
+        };
+        assert_eq!(run(), run());
+    }
+}
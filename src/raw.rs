@@ -1,3 +1,4 @@
+use crate::config::Config;
 use crate::data::{Class, Medigun, TeamId};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize};
@@ -131,6 +132,13 @@ pub struct Player {
     pub medicstat: Option<MedicStats>,
 }
 
+impl Player {
+    /// Total time played over all classes
+    pub fn play_time(&self) -> u32 {
+        self.class_stats.iter().map(|class| class.total_time).sum()
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MedicStats {
     pub advantages_lost: u32,
@@ -193,6 +201,21 @@ pub struct WeaponStat {
     pub hits: u32,
 }
 
+impl WeaponStat {
+    /// Add the stats of another use of the same weapon
+    pub fn add(&mut self, other: &WeaponStat) {
+        let hits = self.hits + other.hits;
+        if hits > 0 {
+            self.avg_dmg =
+                (self.avg_dmg * self.hits as f32 + other.avg_dmg * other.hits as f32) / hits as f32;
+        }
+        self.kills += other.kills;
+        self.dmg += other.dmg;
+        self.shots += other.shots;
+        self.hits = hits;
+    }
+}
+
 impl From<RawWeaponStats> for WeaponStat {
     fn from(raw: RawWeaponStats) -> Self {
         match raw {
@@ -209,21 +232,16 @@ impl From<RawWeaponStats> for WeaponStat {
                 avg_dmg,
                 shots,
                 hits,
-            } => WeaponStat {
-                kills,
-                dmg: if dmg > 0 && dmg < 100_000 {
-                    dmg as u32
-                } else {
-                    0
-                },
-                avg_dmg: if dmg > 0 && dmg < 100_000 {
-                    avg_dmg
-                } else {
-                    0.0
-                },
-                shots,
-                hits,
-            },
+            } => {
+                let valid_dmg = dmg > 0 && dmg < Config::global().max_weapon_damage;
+                WeaponStat {
+                    kills,
+                    dmg: if valid_dmg { dmg as u32 } else { 0 },
+                    avg_dmg: if valid_dmg { avg_dmg } else { 0.0 },
+                    shots,
+                    hits,
+                }
+            }
         }
     }
 }
@@ -357,33 +375,33 @@ pub struct Info {
     pub total_length: u32,
     #[serde(default)]
     pub supplemental: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasRealDamage")]
     pub has_real_damage: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasWeaponDamage")]
     pub has_weapon_damage: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasAccuracy")]
     pub has_accuracy: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasHP")]
     pub has_hp: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasHP_real")]
     pub has_hp_real: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasHS")]
     pub has_hs: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasHS_hit")]
     pub has_hs_hit: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasBS")]
     pub has_bs: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasCP")]
     pub has_cp: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasSB")]
     pub has_sb: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasDT")]
     pub has_dt: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasAS")]
     pub has_as: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasHR")]
     pub has_hr: bool,
-    #[serde(default)]
+    #[serde(default, alias = "hasIntel")]
     pub has_intel: bool,
     #[serde(default)]
     #[serde(rename = "AD_scoring")]
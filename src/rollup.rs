@@ -0,0 +1,121 @@
+use crate::data::{Class, GameMode};
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::{info, instrument};
+
+/// A row of the player stats rollup that doesn't match the full recomputation
+#[derive(Debug)]
+pub struct RollupMismatch {
+    pub game_mode: GameMode,
+    pub clean_map: String,
+    pub year: i32,
+    pub month: i32,
+    pub class: Class,
+    pub steam_id: i64,
+}
+
+/// Add the contribution of the logs to the player stats rollup
+pub async fn add_to_rollup(
+    tx: &mut Transaction<'_, Postgres>,
+    ids: &[i32],
+) -> Result<(), sqlx::Error> {
+    apply(tx, ids, 1).await
+}
+
+/// Remove the contribution of the logs from the player stats rollup, needs to be done before the logs are changed
+pub async fn subtract_from_rollup(
+    tx: &mut Transaction<'_, Postgres>,
+    ids: &[i32],
+) -> Result<(), sqlx::Error> {
+    apply(tx, ids, -1).await?;
+    sqlx::query!(
+        "DELETE FROM player_stats WHERE count = 0 \
+            AND steam_id IN (SELECT steam_id FROM players WHERE log_id = ANY($1))",
+        ids
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+async fn apply(
+    tx: &mut Transaction<'_, Postgres>,
+    ids: &[i32],
+    sign: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO player_stats(game_mode, clean_map, year, month, class, steam_id, damage, kills, deaths, \
+            assists, time, heals_received, damage_taken, count, wins) \
+        SELECT game_mode, clean_map, year, month, class, steam_id, $2::INTEGER * sum(damage), $2::INTEGER * sum(kills), \
+            $2::INTEGER * sum(deaths), $2::INTEGER * sum(assists), $2::INTEGER * sum(time), $2::INTEGER * sum(heals_received), \
+            $2::INTEGER * sum(damage_taken), $2::INTEGER * sum(count), $2::INTEGER * sum(wins) \
+        FROM player_stats_contributions WHERE log_id = ANY($1) \
+        GROUP BY game_mode, clean_map, year, month, class, steam_id \
+        ON CONFLICT (game_mode, clean_map, year, month, steam_id, class) DO UPDATE SET \
+            damage = player_stats.damage + EXCLUDED.damage, \
+            kills = player_stats.kills + EXCLUDED.kills, \
+            deaths = player_stats.deaths + EXCLUDED.deaths, \
+            assists = player_stats.assists + EXCLUDED.assists, \
+            time = player_stats.time + EXCLUDED.time, \
+            heals_received = player_stats.heals_received + EXCLUDED.heals_received, \
+            damage_taken = player_stats.damage_taken + EXCLUDED.damage_taken, \
+            count = player_stats.count + EXCLUDED.count, \
+            wins = player_stats.wins + EXCLUDED.wins",
+        ids,
+        sign
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Compare the player stats rollup to a full recomputation from all logs
+#[instrument(skip(pool))]
+pub async fn check_rollup(pool: &PgPool) -> Result<Vec<RollupMismatch>, sqlx::Error> {
+    sqlx::query_as!(
+        RollupMismatch,
+        r#"WITH full_stats AS (
+            SELECT game_mode, clean_map, year, month, class, steam_id, sum(damage)::BIGINT AS damage,
+                sum(kills)::BIGINT AS kills, sum(deaths)::BIGINT AS deaths, sum(assists)::BIGINT AS assists,
+                sum(time)::BIGINT AS time, sum(heals_received)::BIGINT AS heals_received,
+                sum(damage_taken)::BIGINT AS damage_taken, sum(count)::BIGINT AS count, sum(wins)::BIGINT AS wins
+            FROM player_stats_contributions
+            GROUP BY game_mode, clean_map, year, month, class, steam_id
+        ), rollup AS (
+            SELECT game_mode, clean_map, year, month, class, steam_id, damage, kills, deaths, assists, time,
+                heals_received, damage_taken, count, wins
+            FROM player_stats
+        ), mismatches AS (
+            (SELECT * FROM full_stats EXCEPT SELECT * FROM rollup)
+            UNION
+            (SELECT * FROM rollup EXCEPT SELECT * FROM full_stats)
+        )
+        SELECT DISTINCT game_mode AS "game_mode!: GameMode", clean_map AS "clean_map!", year AS "year!",
+            month AS "month!", class AS "class!: Class", steam_id AS "steam_id!"
+        FROM mismatches"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Recompute the player stats rollup from all logs
+#[instrument(skip(pool))]
+pub async fn rebuild_rollup(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM player_stats")
+        .execute(&mut *tx)
+        .await?;
+    let rows = sqlx::query!(
+        "INSERT INTO player_stats(game_mode, clean_map, year, month, class, steam_id, damage, kills, deaths, \
+            assists, time, heals_received, damage_taken, count, wins) \
+        SELECT game_mode, clean_map, year, month, class, steam_id, sum(damage), sum(kills), sum(deaths), \
+            sum(assists), sum(time), sum(heals_received), sum(damage_taken), sum(count), sum(wins) \
+        FROM player_stats_contributions \
+        GROUP BY game_mode, clean_map, year, month, class, steam_id"
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+    info!(rows, "rebuilt player stats rollup");
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// Set once SIGTERM or SIGINT is received, the normalizer stops after the log it's working on
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Start listening for the shutdown signals, a second signal exits immediately
+    pub fn listen() -> std::io::Result<Self> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let (sender, requested) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = interrupt.recv() => {}
+            }
+            info!("shutdown requested, stopping after the current log");
+            let _ = sender.send(true);
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = interrupt.recv() => {}
+            }
+            error!("shutdown requested again, exiting immediately");
+            std::process::exit(1);
+        });
+        Ok(Shutdown { requested })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Wait until a shutdown is requested
+    pub async fn wait(&mut self) {
+        if self
+            .requested
+            .wait_for(|requested| *requested)
+            .await
+            .is_err()
+        {
+            warn!("stopped listening for shutdown signals");
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{timeout, Duration};
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (sender, requested) = watch::channel(false);
+        let mut shutdown = Shutdown { requested };
+        assert!(!shutdown.is_requested());
+        assert!(timeout(Duration::from_millis(10), shutdown.wait())
+            .await
+            .is_err());
+
+        sender.send(true).unwrap();
+        assert!(shutdown.is_requested());
+        assert!(timeout(Duration::from_millis(10), shutdown.wait())
+            .await
+            .is_ok());
+    }
+}
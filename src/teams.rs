@@ -0,0 +1,199 @@
+use crate::config::Config;
+use crate::data::TeamId;
+use crate::normalized::NormalizedLog;
+use sqlx::{Postgres, Transaction};
+use std::collections::HashSet;
+use tracing::{debug, instrument};
+
+/// A persistent team identity with its most recent roster
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownTeam {
+    pub id: i32,
+    pub roster: HashSet<i64>,
+}
+
+/// The players that played at least half of the log for the team, ignoring short substitutes
+pub fn core_roster(log: &NormalizedLog, team: TeamId) -> HashSet<i64> {
+    let length = log.median_play_time();
+    log.players
+        .iter()
+        .filter(|(_, player)| player.team == Some(team))
+        .filter(|(_, player)| player.play_time() * 2 >= length)
+        .map(|(steam_id, _)| u64::from(*steam_id) as i64)
+        .collect()
+}
+
+/// Find the team that shares the most players with the roster, if it shares enough of both the roster and its own roster
+pub fn best_team<'a>(
+    teams: impl IntoIterator<Item = &'a KnownTeam>,
+    roster: &HashSet<i64>,
+    min_overlap: f32,
+) -> Option<&'a KnownTeam> {
+    if roster.is_empty() {
+        return None;
+    }
+    teams
+        .into_iter()
+        .map(|team| (team, team.roster.intersection(roster).count()))
+        .filter(|(team, shared)| {
+            // a small roster shouldn't match a large team it's a part of, or the other way around
+            let largest = roster.len().max(team.roster.len());
+            *shared as f32 / largest as f32 >= min_overlap
+        })
+        .max_by_key(|(team, shared)| (*shared, -team.id))
+        .map(|(team, _)| team)
+}
+
+/// Link both sides of the log to a persistent team, creating new teams for unknown rosters
+#[instrument(skip(tx, log))]
+pub async fn assign_teams(
+    tx: &mut Transaction<'_, Postgres>,
+    id: i32,
+    log: &NormalizedLog,
+) -> Result<(), sqlx::Error> {
+    let mut assigned = None;
+    for side in [TeamId::Red, TeamId::Blue] {
+        let roster = core_roster(log, side);
+        // a single player isn't a team
+        if roster.len() < 2 {
+            continue;
+        }
+        let steam_ids: Vec<i64> = roster.iter().copied().collect();
+        let candidates: Vec<KnownTeam> = sqlx::query!(
+            "SELECT id, roster FROM teams WHERE roster && $1",
+            &steam_ids
+        )
+        .fetch_all(&mut **tx)
+        .await?
+        .into_iter()
+        .filter(|team| Some(team.id) != assigned)
+        .map(|team| KnownTeam {
+            id: team.id,
+            roster: team.roster.into_iter().collect(),
+        })
+        .collect();
+
+        let team_id = match best_team(&candidates, &roster, Config::global().team_roster_overlap) {
+            Some(team) => {
+                debug!(team = team.id, side = debug(side), "found known team");
+                // keep the roster up to date as players join or leave the team, older logs don't replace a newer roster
+                sqlx::query!(
+                    "UPDATE teams SET roster = CASE WHEN $2 >= last_seen THEN $1 ELSE roster END, \
+                        first_seen = LEAST(first_seen, $2), last_seen = GREATEST(last_seen, $2) \
+                    WHERE id = $3",
+                    &steam_ids,
+                    log.info.date().naive_utc(),
+                    team.id
+                )
+                .execute(&mut **tx)
+                .await?;
+                team.id
+            }
+            None => {
+                sqlx::query!(
+                "INSERT INTO teams(roster, first_seen, last_seen) VALUES($1, $2, $2) RETURNING id",
+                &steam_ids,
+                log.info.date().naive_utc(),
+            )
+                .fetch_one(&mut **tx)
+                .await?
+                .id
+            }
+        };
+        sqlx::query!(
+            "INSERT INTO log_teams(log_id, team, team_id) VALUES($1, $2, $3)",
+            id,
+            side as TeamId,
+            team_id
+        )
+        .execute(&mut **tx)
+        .await?;
+        assigned = Some(team_id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// 4 out of 6 players
+    const MIN_OVERLAP: f32 = 4.0 / 6.0;
+
+    fn parse(file: &str) -> NormalizedLog {
+        let content = fs::read_to_string(format!("tests/data/{}", file)).unwrap();
+        serde_json::from_str(&content).unwrap()
+    }
+
+    #[test]
+    fn test_core_roster() {
+        let log = parse("3579548.json");
+
+        assert_eq!(6, core_roster(&log, TeamId::Red).len());
+        assert_eq!(6, core_roster(&log, TeamId::Blue).len());
+    }
+
+    #[test]
+    fn test_best_team() {
+        let log = parse("3579548.json");
+        let red = core_roster(&log, TeamId::Red);
+        let blue = core_roster(&log, TeamId::Blue);
+        let teams = [
+            KnownTeam {
+                id: 1,
+                roster: blue,
+            },
+            KnownTeam {
+                id: 2,
+                roster: red.clone(),
+            },
+        ];
+
+        assert_eq!(
+            Some(2),
+            best_team(&teams, &red, MIN_OVERLAP).map(|team| team.id)
+        );
+
+        // two players replaced still counts as the same team
+        let mut changed: HashSet<i64> = red.iter().skip(2).copied().collect();
+        changed.extend([1, 2]);
+        assert_eq!(
+            Some(2),
+            best_team(&teams, &changed, MIN_OVERLAP).map(|team| team.id)
+        );
+
+        // but three doesn't
+        changed.remove(red.iter().nth(2).unwrap());
+        changed.insert(3);
+        assert_eq!(None, best_team(&teams, &changed, MIN_OVERLAP));
+    }
+
+    #[test]
+    fn test_best_team_subset() {
+        let log = parse("3579548.json");
+        let red = core_roster(&log, TeamId::Red);
+        let subset: HashSet<i64> = red.iter().take(3).copied().collect();
+
+        let teams = [KnownTeam {
+            id: 1,
+            roster: red.clone(),
+        }];
+        assert_eq!(None, best_team(&teams, &subset, MIN_OVERLAP));
+
+        let teams = [KnownTeam {
+            id: 1,
+            roster: subset,
+        }];
+        assert_eq!(None, best_team(&teams, &red, MIN_OVERLAP));
+
+        // a single extra player still matches
+        let mut extra = red.clone();
+        extra.insert(1);
+        let teams = [KnownTeam { id: 1, roster: red }];
+        assert_eq!(
+            Some(1),
+            best_team(&teams, &extra, MIN_OVERLAP).map(|team| team.id)
+        );
+    }
+}
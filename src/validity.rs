@@ -0,0 +1,325 @@
+use crate::config::ValidityConfig;
+use crate::data::{Class, GameMode};
+use crate::normalized::{NormalizedLog, Player};
+use crate::raw::{ClassStat, KillStreak};
+use std::collections::HashMap;
+use std::ops::{BitOr, BitOrAssign};
+use steamid_ng::SteamID;
+
+/// Rules that a log, player, class or kill streak failed, stored as a bitmask next to the `is_valid` flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InvalidReasons(i32);
+
+impl InvalidReasons {
+    pub const NONE: Self = Self(0);
+    pub const TOO_SHORT: Self = Self(1 << 0);
+    pub const TOO_LONG: Self = Self(1 << 1);
+    pub const NO_MAP: Self = Self(1 << 2);
+    pub const OTHER_GAME_MODE: Self = Self(1 << 3);
+    /// The log has been merged into a later part of the same game
+    pub const MERGED: Self = Self(1 << 4);
+    pub const DUPLICATE: Self = Self(1 << 5);
+    /// Set on players and kill streaks of an invalid log
+    pub const INVALID_LOG: Self = Self(1 << 8);
+    pub const TOO_MANY_KILLS: Self = Self(1 << 9);
+    pub const TOO_MANY_DEATHS: Self = Self(1 << 10);
+    pub const TOO_MUCH_DAMAGE_TAKEN: Self = Self(1 << 11);
+    pub const TOO_MUCH_HEALS_RECEIVED: Self = Self(1 << 12);
+    /// Set on the class stats of an invalid player
+    pub const INVALID_PLAYER: Self = Self(1 << 16);
+    pub const TOO_MUCH_DAMAGE: Self = Self(1 << 17);
+    pub const UNKNOWN_CLASS: Self = Self(1 << 18);
+    pub const NO_PLAY_TIME: Self = Self(1 << 19);
+    pub const STREAK_TOO_LONG: Self = Self(1 << 20);
+
+    const LOG_REASONS: &'static [(Self, &'static str)] = &[
+        (Self::TOO_SHORT, "too_short"),
+        (Self::TOO_LONG, "too_long"),
+        (Self::NO_MAP, "no_map"),
+        (Self::OTHER_GAME_MODE, "other_game_mode"),
+        (Self::MERGED, "merged"),
+        (Self::DUPLICATE, "duplicate"),
+    ];
+
+    pub fn is_valid(self) -> bool {
+        self == Self::NONE
+    }
+
+    pub fn bits(self) -> i32 {
+        self.0
+    }
+
+    /// Names of the failed rules that apply to a whole log
+    pub fn log_reason_names(self) -> impl Iterator<Item = &'static str> {
+        Self::LOG_REASONS
+            .iter()
+            .filter(move |(reason, _)| self.0 & reason.0 != 0)
+            .map(|(_, name)| *name)
+    }
+
+    fn check(&mut self, valid: bool, reason: Self) {
+        if !valid {
+            *self |= reason;
+        }
+    }
+}
+
+impl BitOr for InvalidReasons {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for InvalidReasons {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0
+    }
+}
+
+/// Validity of every row stored for a log
+#[derive(Debug, Clone, Default)]
+pub struct LogValidity {
+    pub log: InvalidReasons,
+    pub players: HashMap<SteamID, PlayerValidity>,
+    /// In the same order as the kill streaks of the log
+    pub kill_streaks: Vec<InvalidReasons>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PlayerValidity {
+    pub player: InvalidReasons,
+    pub classes: HashMap<Class, InvalidReasons>,
+    /// Whether the player played enough of the log to count for the player stats and ratings
+    pub played_enough: bool,
+}
+
+/// Decides which logs and players are included in the stats
+pub struct Validator<'a> {
+    config: &'a ValidityConfig,
+}
+
+impl<'a> Validator<'a> {
+    pub fn new(config: &'a ValidityConfig) -> Self {
+        Validator { config }
+    }
+
+    /// Validate the log and everything in it, `extra` are reasons that can't be determined from the log itself
+    pub fn validate(
+        &self,
+        log: &NormalizedLog,
+        game_mode: GameMode,
+        extra: InvalidReasons,
+    ) -> LogValidity {
+        let log_reasons = self.log(log, game_mode) | extra;
+        let heals_received = log.heals_received();
+        let players = log
+            .players
+            .iter()
+            .map(|(steam_id, player)| {
+                let heals_received = heals_received.get(steam_id).copied().unwrap_or_default();
+                let player_reasons = self.player(log_reasons, player, heals_received);
+                let classes = player
+                    .class_stats
+                    .iter()
+                    .map(|class| (class.class, self.class(player_reasons, class)))
+                    .collect();
+                (
+                    *steam_id,
+                    PlayerValidity {
+                        player: player_reasons,
+                        classes,
+                        played_enough: self.played_enough(log, player),
+                    },
+                )
+            })
+            .collect();
+        let kill_streaks = log
+            .kill_streaks
+            .iter()
+            .map(|streak| self.kill_streak(log_reasons, streak))
+            .collect();
+        LogValidity {
+            log: log_reasons,
+            players,
+            kill_streaks,
+        }
+    }
+
+    fn log(&self, log: &NormalizedLog, game_mode: GameMode) -> InvalidReasons {
+        let length = log.info.total_length as i64;
+        let mut reasons = InvalidReasons::NONE;
+        reasons.check(
+            length > self.config.min_length as i64,
+            InvalidReasons::TOO_SHORT,
+        );
+        reasons.check(
+            length < self.config.max_length as i64,
+            InvalidReasons::TOO_LONG,
+        );
+        reasons.check(!log.info.clean_map().is_empty(), InvalidReasons::NO_MAP);
+        reasons.check(
+            game_mode != GameMode::Other,
+            InvalidReasons::OTHER_GAME_MODE,
+        );
+        reasons
+    }
+
+    fn player(
+        &self,
+        log_reasons: InvalidReasons,
+        player: &Player,
+        heals_received: u32,
+    ) -> InvalidReasons {
+        let mut reasons = InvalidReasons::NONE;
+        reasons.check(log_reasons.is_valid(), InvalidReasons::INVALID_LOG);
+        reasons.check(
+            (player.kills as i32) < self.config.max_kills,
+            InvalidReasons::TOO_MANY_KILLS,
+        );
+        reasons.check(
+            (player.deaths as i32) < self.config.max_deaths,
+            InvalidReasons::TOO_MANY_DEATHS,
+        );
+        reasons.check(
+            (player.dt_real as i64) < self.config.max_damage_taken as i64,
+            InvalidReasons::TOO_MUCH_DAMAGE_TAKEN,
+        );
+        reasons.check(
+            (heals_received as i64) < self.config.max_heals_received as i64,
+            InvalidReasons::TOO_MUCH_HEALS_RECEIVED,
+        );
+        reasons
+    }
+
+    fn played_enough(&self, log: &NormalizedLog, player: &Player) -> bool {
+        player.play_time() as i64 * 100
+            >= log.info.total_length as i64 * self.config.min_play_time_percent as i64
+    }
+
+    fn class(&self, player_reasons: InvalidReasons, class: &ClassStat) -> InvalidReasons {
+        let mut reasons = InvalidReasons::NONE;
+        reasons.check(player_reasons.is_valid(), InvalidReasons::INVALID_PLAYER);
+        reasons.check(
+            (class.kills as i32) < self.config.max_kills,
+            InvalidReasons::TOO_MANY_KILLS,
+        );
+        reasons.check(
+            (class.deaths as i32) < self.config.max_deaths,
+            InvalidReasons::TOO_MANY_DEATHS,
+        );
+        reasons.check(
+            (class.dmg as i64) < self.config.max_class_damage as i64,
+            InvalidReasons::TOO_MUCH_DAMAGE,
+        );
+        reasons.check(class.class != Class::Unknown, InvalidReasons::UNKNOWN_CLASS);
+        reasons.check(class.total_time > 0, InvalidReasons::NO_PLAY_TIME);
+        reasons
+    }
+
+    fn kill_streak(&self, log_reasons: InvalidReasons, streak: &KillStreak) -> InvalidReasons {
+        let mut reasons = InvalidReasons::NONE;
+        reasons.check(log_reasons.is_valid(), InvalidReasons::INVALID_LOG);
+        reasons.check(
+            streak.streak < self.config.max_kill_streak,
+            InvalidReasons::STREAK_TOO_LONG,
+        );
+        reasons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use test_case::test_case;
+
+    fn parse(file: &str) -> NormalizedLog {
+        let content = fs::read_to_string(format!("tests/data/{}", file)).unwrap();
+        serde_json::from_str(&content).unwrap()
+    }
+
+    fn validate(log: &NormalizedLog, extra: InvalidReasons) -> LogValidity {
+        let config = ValidityConfig::default();
+        Validator::new(&config).validate(log, log.detect_game_mode().mode, extra)
+    }
+
+    #[test_case("1.json", InvalidReasons::NO_MAP)]
+    #[test_case("114840.json", InvalidReasons::NO_MAP)]
+    #[test_case("134389.json", InvalidReasons::NONE)]
+    #[test_case("550237.json", InvalidReasons::NONE)]
+    #[test_case("2522305.json", InvalidReasons::NONE)]
+    #[test_case("3578739.json", InvalidReasons::NONE)]
+    #[test_case("3579548.json", InvalidReasons::NONE)]
+    fn test_fixtures(file: &str, expected: InvalidReasons) {
+        let log = parse(file);
+        let validity = validate(&log, InvalidReasons::NONE);
+        assert_eq!(expected, validity.log);
+
+        if !expected.is_valid() {
+            for player in validity.players.values() {
+                assert!(player.player.bits() & InvalidReasons::INVALID_LOG.bits() != 0);
+                for class in player.classes.values() {
+                    assert!(class.bits() & InvalidReasons::INVALID_PLAYER.bits() != 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_extra_reasons() {
+        let log = parse("3579548.json");
+        let validity = validate(&log, InvalidReasons::DUPLICATE);
+        assert_eq!(InvalidReasons::DUPLICATE, validity.log);
+        assert_eq!(
+            vec!["duplicate"],
+            validity.log.log_reason_names().collect::<Vec<_>>()
+        );
+        assert!(validity
+            .players
+            .values()
+            .all(|player| player.player == InvalidReasons::INVALID_LOG));
+        assert!(validity
+            .kill_streaks
+            .iter()
+            .all(|streak| streak.bits() & InvalidReasons::INVALID_LOG.bits() != 0));
+    }
+
+    #[test]
+    fn test_played_enough() {
+        let mut log = parse("3579548.json");
+        let length = log.info.total_length;
+        let steam_id = *log.players.keys().next().unwrap();
+        let player = log.players.get_mut(&steam_id).unwrap();
+        player.class_stats.truncate(1);
+        player.class_stats[0].total_time = length.div_ceil(4);
+        assert!(validate(&log, InvalidReasons::NONE).players[&steam_id].played_enough);
+
+        log.players.get_mut(&steam_id).unwrap().class_stats[0].total_time = length.div_ceil(4) - 1;
+        let validity = validate(&log, InvalidReasons::NONE);
+        assert!(!validity.players[&steam_id].played_enough);
+        assert!(validity.players[&steam_id].player.is_valid());
+    }
+
+    #[test]
+    fn test_player_limits() {
+        let mut log = parse("3579548.json");
+        let steam_id = *log.players.keys().next().unwrap();
+        log.players.get_mut(&steam_id).unwrap().kills = 150;
+
+        let validity = validate(&log, InvalidReasons::NONE);
+        assert!(validity.log.is_valid());
+        let player = &validity.players[&steam_id];
+        assert_eq!(InvalidReasons::TOO_MANY_KILLS, player.player);
+        assert!(player
+            .classes
+            .values()
+            .all(|class| class.bits() & InvalidReasons::INVALID_PLAYER.bits() != 0));
+        assert!(validity
+            .players
+            .iter()
+            .filter(|(id, _)| **id != steam_id)
+            .all(|(_, player)| player.player.is_valid()));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::health::Health;
+use sqlx::PgPool;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument};
+
+/// Materialized views in the order they need to be refreshed, views come after the views they are built from
+pub const VIEWS: &[&str] = &["weapon_stats", "player_names", "user_names"];
+
+/// Refreshes the materialized views after logs have been stored, at most once per interval
+#[derive(Debug)]
+pub struct ViewRefresher {
+    enabled: bool,
+    min_interval: Duration,
+    last_refresh: Option<Instant>,
+    /// Whether logs have changed since the last refresh
+    pending: bool,
+}
+
+impl ViewRefresher {
+    pub fn new(enabled: bool, min_interval: Duration) -> Self {
+        ViewRefresher {
+            enabled,
+            min_interval,
+            last_refresh: None,
+            pending: false,
+        }
+    }
+
+    /// Mark the views as out of date
+    pub fn invalidate(&mut self) {
+        self.pending = true;
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.enabled
+            && self.pending
+            && self
+                .last_refresh
+                .is_none_or(|last| now.duration_since(last) >= self.min_interval)
+    }
+
+    /// Refresh all views if logs have changed and the last refresh is long enough ago
+    #[instrument(skip_all)]
+    pub async fn refresh(&mut self, pool: &PgPool, health: &Health) -> Result<(), sqlx::Error> {
+        let now = Instant::now();
+        if !self.is_due(now) {
+            if self.pending {
+                debug!("skipping view refresh");
+            }
+            return Ok(());
+        }
+        for view in VIEWS {
+            let start = Instant::now();
+            sqlx::query(&format!("REFRESH MATERIALIZED VIEW CONCURRENTLY {}", view))
+                .execute(pool)
+                .await?;
+            info!(
+                view,
+                duration = debug(start.elapsed()),
+                "refreshed materialized view"
+            );
+            health.progress();
+        }
+        self.last_refresh = Some(now);
+        self.pending = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_due() {
+        let now = Instant::now();
+        let mut refresher = ViewRefresher::new(true, Duration::from_secs(60));
+        assert!(!refresher.is_due(now));
+
+        refresher.invalidate();
+        assert!(refresher.is_due(now));
+
+        refresher.last_refresh = Some(now);
+        assert!(!refresher.is_due(now + Duration::from_secs(30)));
+        assert!(refresher.is_due(now + Duration::from_secs(60)));
+
+        let mut disabled = ViewRefresher::new(false, Duration::ZERO);
+        disabled.invalidate();
+        assert!(!disabled.is_due(now));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::data::{Class, WeaponSlot};
+use anyhow::{Context, Error};
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::sync::{Mutex, OnceLock};
+use tracing::{instrument, warn};
+
+const DEFAULT_REGISTRY: &str = include_str!("../weapons.toml");
+
+static REGISTRY: OnceLock<WeaponRegistry> = OnceLock::new();
+
+/// Unknown weapons that were already reported in the current pass
+static REPORTED_UNKNOWN: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Known weapons with their canonical name and metadata, loaded from `weapons.toml`
+#[derive(Debug, Clone)]
+pub struct WeaponRegistry {
+    weapons: Vec<WeaponInfo>,
+    /// Index into `weapons` by id and alias
+    names: HashMap<String, usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegistryFile {
+    #[serde(default, rename = "weapon")]
+    weapons: Vec<WeaponInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct WeaponInfo {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// The class that uses the weapon, if it's limited to a single class
+    #[serde(default)]
+    pub class: Option<Class>,
+    pub slot: WeaponSlot,
+    #[serde(default)]
+    pub stock: bool,
+}
+
+impl WeaponRegistry {
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let file: RegistryFile =
+            toml::from_str(content).context("Failed to parse weapon registry")?;
+        let mut names = HashMap::new();
+        for (index, weapon) in file.weapons.iter().enumerate() {
+            for name in std::iter::once(&weapon.id).chain(&weapon.aliases) {
+                if names.insert(name.to_ascii_lowercase(), index).is_some() {
+                    anyhow::bail!("Duplicate weapon name {} in weapon registry", name);
+                }
+            }
+        }
+        Ok(WeaponRegistry {
+            weapons: file.weapons,
+            names,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read weapon registry {}", path))?;
+        Self::parse(&content)
+    }
+
+    /// Set the registry used for normalizing weapon names, can only be done once
+    pub fn init(registry: WeaponRegistry) {
+        if REGISTRY.set(registry).is_err() {
+            panic!("weapon registry already initialized");
+        }
+    }
+
+    /// The registry set by [`WeaponRegistry::init`], or the bundled registry if none has been set
+    pub fn global() -> &'static WeaponRegistry {
+        REGISTRY
+            .get_or_init(|| Self::parse(DEFAULT_REGISTRY).expect("invalid bundled weapon registry"))
+    }
+
+    pub fn weapons(&self) -> &[WeaponInfo] {
+        &self.weapons
+    }
+
+    /// Find the weapon by its id or any of its aliases
+    pub fn lookup(&self, weapon: &str) -> Option<&WeaponInfo> {
+        self.names
+            .get(&weapon.to_ascii_lowercase())
+            .map(|index| &self.weapons[*index])
+    }
+}
+
+/// Warn about a weapon that isn't in the registry, once per pass, returns whether it was reported
+pub fn report_unknown(weapon: &str) -> bool {
+    let mut reported = REPORTED_UNKNOWN.lock().unwrap();
+    if reported.contains(weapon) {
+        return false;
+    }
+    warn!(weapon, "unknown weapon");
+    reported.insert(weapon.to_string());
+    true
+}
+
+/// Report unknown weapons again in the next pass
+pub fn reset_unknown_reports() {
+    REPORTED_UNKNOWN.lock().unwrap().clear();
+}
+
+/// Update the weapons table to match the registry
+#[instrument(skip(pool, registry))]
+pub async fn sync_weapons(pool: &PgPool, registry: &WeaponRegistry) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for weapon in registry.weapons() {
+        sqlx::query!(
+            "INSERT INTO weapons(id, name, class, slot, stock) VALUES($1, $2, $3, $4, $5) \
+            ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name, class = EXCLUDED.class, \
+            slot = EXCLUDED.slot, stock = EXCLUDED.stock",
+            weapon.id,
+            weapon.name,
+            weapon.class as Option<Class>,
+            weapon.slot as WeaponSlot,
+            weapon.stock
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use test_case::test_case;
+
+    #[test_case("scattergun", "scattergun", WeaponSlot::Primary)]
+    #[test_case("quake_rl", "the_original", WeaponSlot::Primary)]
+    #[test_case("tf_projectile_rocket", "rocketlauncher", WeaponSlot::Primary)]
+    #[test_case("TF_Projectile_Pipe", "grenade_launcher", WeaponSlot::Primary)]
+    #[test_case("obj_sentrygun3", "sentry_gun", WeaponSlot::Building)]
+    #[test_case("deflect_rocket", "reflect", WeaponSlot::Other)]
+    #[test_case("fryingpan", "fryingpan", WeaponSlot::Melee)]
+    fn test_lookup(weapon: &str, id: &str, slot: WeaponSlot) {
+        let info = WeaponRegistry::global().lookup(weapon).unwrap();
+        assert_eq!(id, info.id);
+        assert_eq!(slot, info.slot);
+    }
+
+    #[test]
+    fn test_report_unknown() {
+        assert!(report_unknown("test_report_unknown_weapon"));
+        assert!(!report_unknown("test_report_unknown_weapon"));
+        reset_unknown_reports();
+        assert!(report_unknown("test_report_unknown_weapon"));
+    }
+
+    #[test]
+    fn test_unknown() {
+        assert_eq!(None, WeaponRegistry::global().lookup("not_a_weapon"));
+    }
+
+    #[test]
+    fn test_duplicate_alias() {
+        let registry = r#"
+            [[weapon]]
+            id = "a"
+            name = "A"
+            slot = "primary"
+
+            [[weapon]]
+            id = "b"
+            name = "B"
+            aliases = ["a"]
+            slot = "primary"
+        "#;
+        assert!(WeaponRegistry::parse(registry).is_err());
+    }
+
+    #[test]
+    fn test_fixtures() {
+        for file in [
+            "1.json",
+            "114840.json",
+            "134389.json",
+            "550237.json",
+            "2522305.json",
+            "3578739.json",
+            "3579548.json",
+        ] {
+            let content = std::fs::read_to_string(format!("tests/data/{}", file)).unwrap();
+            let log: Value = serde_json::from_str(&content).unwrap();
+            for player in log["players"].as_object().unwrap().values() {
+                for class in player["class_stats"].as_array().unwrap() {
+                    let weapons = class["weapon"].as_object();
+                    for weapon in weapons.into_iter().flat_map(|weapons| weapons.keys()) {
+                        assert!(
+                            WeaponRegistry::global().lookup(weapon).is_some(),
+                            "{}: unknown weapon {}",
+                            file,
+                            weapon
+                        );
+                    }
+                }
+            }
+        }
+    }
+}